@@ -116,7 +116,7 @@ fn follow(
 // Bevy systems (see the `done` example). Also consider implementing the `EntityTrigger` trait
 // directly.
 #[allow(dead_code)]
-fn near(target: Entity) -> impl EntityTrigger<Out = Result<f32, f32>> {
+fn near(target: Entity) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     (move |In(entity): In<Entity>, transforms: Query<&Transform>| {
         let distance = transforms
             .get(target)
@@ -0,0 +1,292 @@
+//! Loading state machines from RON or JSON files. See [`StateMachineAsset`] and [`MachineAsset`].
+
+use std::collections::HashSet;
+
+use bevy_asset::{io::Reader, prelude::*, AssetLoader, LoadContext};
+use bevy_reflect::TypePath;
+
+use crate::{data::MachineData, machine::reload_machine, prelude::*, ErrList, OK};
+
+pub(crate) fn plug(app: &mut App) {
+    // Asset machines need an `AssetServer`, which isn't available without `AssetPlugin`
+    if !app.world().contains_resource::<AssetServer>() {
+        return;
+    }
+
+    app.init_asset::<StateMachineAsset>()
+        .register_asset_loader(StateMachineAssetLoader)
+        .add_systems(
+            PreUpdate,
+            (reload_asset_machines, build_asset_machines).chain(),
+        );
+}
+
+/// A state machine definition loaded from a `.machine.ron` or `.machine.json` file. The file
+/// contains a [`MachineData`], which is built with `MachineRegistry::build_data`.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct StateMachineAsset(pub MachineData);
+
+/// Loads [`StateMachineAsset`]s from RON, or from JSON if the file's extension ends in `json`
+#[derive(TypePath, Default)]
+pub struct StateMachineAssetLoader;
+
+impl AssetLoader for StateMachineAssetLoader {
+    type Asset = StateMachineAsset;
+    type Settings = ();
+    type Error = BevyError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<StateMachineAsset> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let json = load_context
+            .path()
+            .get_full_extension()
+            .is_some_and(|extension| extension.ends_with("json"));
+        let data = if json {
+            serde_json::from_slice(&bytes)?
+        } else {
+            ron::de::from_bytes(&bytes)?
+        };
+
+        Ok(StateMachineAsset(data))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["machine.ron", "machine.json"]
+    }
+}
+
+/// Gives an entity a `StateMachine` built from the given asset, once it's loaded. The machine is
+/// built with the `MachineRegistry` resource, and named after the asset's path. Add the entity's
+/// initial state along with this component.
+///
+/// When the asset is modified, like when its file is edited with hot reloading enabled, the
+/// entity's machine is rebuilt and replaced, and its triggers are initialized again. The entity
+/// stays in its states if the new machine has them. Otherwise, it moves to the machine's fallback
+/// state (see `MachineData::fallback`), like with `TransitionCommands::transition_to`, running
+/// the old machine's events. If there isn't a fallback, it keeps its old machine.
+#[derive(Component, Clone, Debug)]
+pub struct MachineAsset(pub Handle<StateMachineAsset>);
+
+/// Builds the machines of entities with a [`MachineAsset`] and no `StateMachine`
+fn build_asset_machines(
+    mut commands: Commands,
+    entities: Query<(Entity, &MachineAsset), Without<StateMachine>>,
+    assets: Res<Assets<StateMachineAsset>>,
+    registry: Option<Res<MachineRegistry>>,
+    // Assets that couldn't be built, so the error is only reported once
+    mut failed: Local<HashSet<AssetId<StateMachineAsset>>>,
+    mut events: MessageReader<AssetEvent<StateMachineAsset>>,
+) -> Result {
+    // Modified assets may build now
+    for event in events.read() {
+        if let AssetEvent::Modified { id } = event {
+            failed.remove(id);
+        }
+    }
+
+    let mut errs = ErrList::default();
+
+    for (entity, MachineAsset(handle)) in &entities {
+        let Some(StateMachineAsset(data)) = assets.get(handle) else {
+            continue;
+        };
+
+        if failed.contains(&handle.id()) {
+            continue;
+        }
+
+        let Some(registry) = &registry else {
+            failed.insert(handle.id());
+            errs.push(Err::<(), _>(
+                "`MachineAsset` requires the `MachineRegistry` resource",
+            ));
+            continue;
+        };
+
+        let name = handle
+            .path()
+            .map_or_else(|| format!("{:?}", handle.id()), ToString::to_string);
+        let machine = registry
+            .build_data(data)
+            .map_err(|err| format!("couldn't build state machine `{name}`: {err}"));
+        let Some(machine) = errs.push(machine) else {
+            failed.insert(handle.id());
+            continue;
+        };
+
+        commands.entity(entity).insert(machine.with_name(name));
+    }
+
+    errs.into()
+}
+
+/// Replaces the machines of entities whose [`MachineAsset`] was modified
+fn reload_asset_machines(
+    mut commands: Commands,
+    mut events: MessageReader<AssetEvent<StateMachineAsset>>,
+    entities: Query<(Entity, &MachineAsset, &StateMachine)>,
+    assets: Res<Assets<StateMachineAsset>>,
+    registry: Option<Res<MachineRegistry>>,
+) -> Result {
+    let modified = events
+        .read()
+        .filter_map(|event| match *event {
+            AssetEvent::Modified { id } => Some(id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    if modified.is_empty() {
+        return OK;
+    }
+
+    let Some(registry) = registry else {
+        return Err("`MachineAsset` requires the `MachineRegistry` resource".into());
+    };
+
+    let mut errs = ErrList::default();
+
+    for (entity, MachineAsset(handle), old) in &entities {
+        if !modified.contains(&handle.id()) {
+            continue;
+        }
+
+        let Some(StateMachineAsset(data)) = assets.get(handle) else {
+            continue;
+        };
+
+        let name = old.name().unwrap_or_default().to_string();
+        let machine = registry
+            .build_data(data)
+            .map_err(|err| format!("couldn't reload state machine `{name}`: {err}"));
+        let Some(machine) = errs.push(machine) else {
+            continue;
+        };
+
+        let fallback = data.fallback.as_ref().map(|fallback| {
+            registry
+                .data_value(fallback)
+                .map_err(|err| format!("in the fallback state of `{name}`: {err}"))
+        });
+        let Some(fallback) = errs.push(fallback.transpose()) else {
+            continue;
+        };
+
+        commands
+            .entity(entity)
+            .queue(move |entity: EntityWorldMut| {
+                reload_machine(entity, machine.with_name(name), fallback)
+            });
+    }
+
+    errs.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        machine::transition,
+        registry::fixture::{registry, Idle, Walk},
+    };
+
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.insert_resource(registry())
+            .init_resource::<Assets<StateMachineAsset>>()
+            .add_message::<AssetEvent<StateMachineAsset>>()
+            .add_systems(
+                Update,
+                (reload_asset_machines, build_asset_machines, transition).chain(),
+            );
+        #[cfg(feature = "reflect")]
+        app.add_systems(
+            Update,
+            crate::reflect::update_machine_info.after(transition),
+        );
+        app
+    }
+
+    fn machine(data: &str) -> StateMachineAsset {
+        StateMachineAsset(ron::from_str(data).unwrap())
+    }
+
+    #[test]
+    fn test_ron_machine() {
+        let mut app = app();
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<StateMachineAsset>>()
+            .add(machine(
+                r#"(
+                    transitions: [
+                        (from: "idle", to: (state: "walk", value: 2), trigger: "always"),
+                    ],
+                )"#,
+            ));
+        let entity = app.world_mut().spawn((MachineAsset(handle), Idle)).id();
+
+        app.update();
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(2)));
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut app = app();
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<StateMachineAsset>>()
+            .add(machine(
+                r#"(transitions: [(from: "idle", to: (state: "walk", value: 1), trigger: "always")])"#,
+            ));
+        let entity = app
+            .world_mut()
+            .spawn((MachineAsset(handle.clone()), Idle, StateHistory::new(2)))
+            .id();
+        app.update();
+
+        let reload = |app: &mut App, data| {
+            let id = handle.id();
+            app.world_mut()
+                .resource_mut::<Assets<StateMachineAsset>>()
+                .insert(id, machine(data))
+                .unwrap();
+            app.world_mut().write_message(AssetEvent::Modified { id });
+            app.update();
+        };
+
+        // The entity stays in `Walk`, since the new machine has it
+        reload(
+            &mut app,
+            r#"(transitions: [(from: "walk", to: "run", trigger: (not: "always"))])"#,
+        );
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(1)));
+        // The entity's info describes the new machine, though its state didn't change
+        #[cfg(feature = "reflect")]
+        {
+            use crate::registry::fixture::Run;
+
+            let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+            assert_eq!(info.transitions.len(), 1);
+            assert_eq!(info.transitions[0].target, std::any::type_name::<Run>());
+        }
+
+        // `Walk` was removed, so the entity enters the fallback state
+        reload(
+            &mut app,
+            r#"(transitions: [(from: "run", to: "idle", trigger: (not: "always"))], fallback: Some("idle"))"#,
+        );
+        assert!(app.world().get::<Walk>(entity).is_none());
+        assert!(app.world().get::<Idle>(entity).is_some());
+        // Like a transition, leaving `Walk` is recorded
+        let history = app.world().get::<StateHistory>(entity).unwrap();
+        assert_eq!(history.len(), 2);
+    }
+}
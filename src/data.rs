@@ -0,0 +1,486 @@
+//! Building state machines from data, so they can be edited without recompiling. See
+//! [`MachineData`] and `MachineRegistry::build_data`.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    prelude::*,
+    registry::{erase_trigger, DataEvent, DataTrigger, RegisteredState},
+    state::StateValue,
+};
+
+/// The name that stands for `AnyState` in [`MachineData`]
+pub const ANY_STATE: &str = "*";
+
+/// A state machine described as data. States, triggers, and events are referred to by the names
+/// they're registered with in the `MachineRegistry`. Build it with `MachineRegistry::build_data`.
+///
+/// In RON, it looks like this:
+///
+/// ```ron
+/// (
+///     transitions: [
+///         (from: "idle", to: "walk", trigger: (trigger: "near_player", params: 5.0)),
+///         (from: ["idle", "walk"], to: (state: "attack", value: 10), trigger: "in_range"),
+///         (from: "*", to: "dead", trigger: (not: "alive"), priority: 10),
+///     ],
+///     on_enter: [(state: "attack", event: "play_sound", params: "swing")],
+///     initial: Some("idle"),
+///     fallback: Some("idle"),
+/// )
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct MachineData {
+    /// States that aren't used in any transitions, but should be registered anyway. See
+    /// `StateMachine::with_state`.
+    pub states: Vec<String>,
+    /// The machine's transitions
+    pub transitions: Vec<TransitionData>,
+    /// The machine's on-enter events, in the order they run
+    pub on_enter: Vec<EventData>,
+    /// The machine's on-exit events, in the order they run
+    pub on_exit: Vec<EventData>,
+    /// The state that entities enter when the machine is added to them, if they're in none of its
+    /// states. See `StateMachine::initial`.
+    pub initial: Option<StateData>,
+    /// The state that entities enter when their machine is reloaded, if the state they're in was
+    /// removed from the machine. See `MachineAsset`.
+    pub fallback: Option<StateData>,
+    /// How the machine chooses between transitions that are triggered at once. See
+    /// `StateMachine::with_selection`. Can't be `Selection::Utility`, since triggers built from
+    /// data don't keep their scores.
+    pub selection: Selection,
+    /// If set, the machine runs to completion, checking its transitions at most this many times
+    /// per run, which must be at least 1. See `StateMachine::run_to_completion`.
+    pub run_to_completion: Option<u32>,
+    /// What the machine does when an entity's state is changed by something other than the
+    /// machine. See `StateMachine::with_external_changes`.
+    pub external_changes: ExternalChanges,
+}
+
+/// A transition in [`MachineData`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransitionData {
+    /// The states that the transition may be taken from
+    pub from: SourceData,
+    /// The state that the transition goes to
+    pub to: StateData,
+    /// The transition's trigger
+    pub trigger: TriggerData,
+    /// Transitions with higher priorities are checked first. Transitions with the same priority are
+    /// checked in the order they're listed. Defaults to 0. See `StateMachine::with_priority`.
+    #[serde(default)]
+    pub priority: i32,
+    /// The transition's weight, for `Selection::Weighted`. Defaults to 1. See
+    /// `StateMachine::with_weight`.
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.
+}
+
+/// The states that a transition may be taken from, by registered name. [`ANY_STATE`] stands for
+/// `AnyState`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum SourceData {
+    /// A single state
+    One(String),
+    /// Any of the given states. This adds one transition for each state.
+    Many(Vec<String>),
+}
+
+/// A state value in [`MachineData`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum StateData {
+    /// A state or constructor name, built from `null`. Useful for unit structs.
+    Name(String),
+    /// A state or constructor name, with the data to build it from
+    Value {
+        /// The registered name
+        state: String,
+        /// The state's value, or the constructor's parameters
+        #[serde(default)]
+        value: Value,
+    },
+}
+
+/// A trigger in [`MachineData`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TriggerData {
+    /// A registered trigger, built from `null`
+    Name(String),
+    /// A registered trigger, with the parameters to build it from
+    Params {
+        /// The registered name
+        trigger: String,
+        /// The trigger's parameters
+        #[serde(default)]
+        params: Value,
+    },
+    /// Occurs when the given trigger doesn't. See `IntoTrigger::not`.
+    Not {
+        /// The trigger to negate
+        not: Box<TriggerData>,
+    },
+    /// Occurs when all of the given triggers occur. See `IntoTrigger::and`.
+    And {
+        /// The triggers to combine
+        and: Vec<TriggerData>,
+    },
+    /// Occurs when any of the given triggers occur. See `IntoTrigger::or`.
+    Or {
+        /// The triggers to combine
+        or: Vec<TriggerData>,
+    },
+}
+
+/// An on-enter or on-exit event in [`MachineData`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventData {
+    /// The registered name of the state that is entered or exited. [`ANY_STATE`] stands for
+    /// `AnyState`.
+    pub state: String,
+    /// The registered name of the event
+    pub event: String,
+    /// The event's parameters
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl MachineRegistry {
+    /// Builds a state machine from data. Transitions are added in order, with their priorities and
+    /// weights. Returns an error naming the unknown state, trigger, or event if the data refers to
+    /// one that isn't registered, or if the machine chooses transitions by utility.
+    pub fn build_data(&self, data: &MachineData) -> Result<StateMachine> {
+        if let Selection::Utility { .. } = data.selection {
+            return Err(
+                "`Selection::Utility` can't be used in `MachineData`, since triggers built from \
+                data don't keep their scores"
+                    .into(),
+            );
+        }
+
+        let mut machine = StateMachine::default();
+
+        for state in &data.states {
+            machine = (self.data_state(state)?.with_state)(machine);
+        }
+
+        if let Some(initial) = &data.initial {
+            let initial = self.data_value(initial);
+            machine = machine
+                .with_initial_value(initial.map_err(|err| format!("in the initial state: {err}"))?);
+        }
+
+        if let Some(fallback) = &data.fallback {
+            let fallback = self.data_value(fallback);
+            machine = fallback
+                .map_err(|err| format!("in the fallback state: {err}"))?
+                .register(machine);
+        }
+
+        machine = machine
+            .with_selection(data.selection.validate()?)
+            .with_external_changes(data.external_changes);
+        if let Some(max_iterations) = data.run_to_completion {
+            if max_iterations == 0 {
+                return Err("`run_to_completion` must be at least 1".into());
+            }
+            machine = machine.run_to_completion(max_iterations);
+        }
+
+        for (index, transition) in data.transitions.iter().enumerate() {
+            machine = self
+                .data_transition(machine, transition)
+                .map_err(|err| format!("in transition {index}: {err}"))?;
+        }
+
+        for (index, event) in data.on_enter.iter().enumerate() {
+            let build = self.data_event(event);
+            let build = build.map_err(|err| format!("in on-enter event {index}: {err}"))?;
+            machine = match build {
+                (None, event) => machine.on_enter::<AnyState>(move |entity| event(entity)),
+                (Some(state), event) => (state.on_enter)(machine, event),
+            };
+        }
+
+        for (index, event) in data.on_exit.iter().enumerate() {
+            let build = self.data_event(event);
+            let build = build.map_err(|err| format!("in on-exit event {index}: {err}"))?;
+            machine = match build {
+                (None, event) => machine.on_exit::<AnyState>(move |entity| event(entity)),
+                (Some(state), event) => (state.on_exit)(machine, event),
+            };
+        }
+
+        Ok(machine)
+    }
+
+    fn data_transition(
+        &self,
+        mut machine: StateMachine,
+        transition: &TransitionData,
+    ) -> Result<StateMachine> {
+        let sources = match &transition.from {
+            SourceData::One(source) => std::slice::from_ref(source),
+            SourceData::Many(sources) => sources,
+        };
+
+        let trigger = self.data_trigger(&transition.trigger)?;
+        for source in sources {
+            let trigger = trigger.clone();
+            let next = self.data_value(&transition.to)?;
+
+            machine = if source == ANY_STATE {
+                machine.trans_value::<AnyState>(trigger, next)
+            } else {
+                (self.data_state(source)?.trans_from)(machine, trigger, next)
+            }
+            .with_priority(transition.priority)
+            .with_weight(transition.weight);
+        }
+
+        Ok(machine)
+    }
+
+    fn data_trigger(&self, trigger: &TriggerData) -> Result<DataTrigger> {
+        match trigger {
+            TriggerData::Name(name) => self.registered_trigger(name, Value::Null),
+            TriggerData::Params { trigger, params } => {
+                self.registered_trigger(trigger, params.clone())
+            }
+            TriggerData::Not { not } => Ok(erase_trigger(self.data_trigger(not)?.not())),
+            TriggerData::And { and } => {
+                self.combine_triggers(and, "and", |t, u| erase_trigger(t.and(u)))
+            }
+            TriggerData::Or { or } => {
+                self.combine_triggers(or, "or", |t, u| erase_trigger(t.or(u)))
+            }
+        }
+    }
+
+    /// Combines the given triggers, first to last
+    fn combine_triggers(
+        &self,
+        triggers: &[TriggerData],
+        kind: &str,
+        combine: impl Fn(DataTrigger, DataTrigger) -> DataTrigger,
+    ) -> Result<DataTrigger> {
+        let (first, rest) = triggers
+            .split_first()
+            .ok_or_else(|| format!("`{kind}` needs at least one trigger"))?;
+
+        rest.iter()
+            .try_fold(self.data_trigger(first)?, |acc, trigger| {
+                Ok(combine(acc, self.data_trigger(trigger)?))
+            })
+    }
+
+    fn registered_trigger(&self, name: &str, params: Value) -> Result<DataTrigger> {
+        let build = self
+            .triggers
+            .get(name)
+            .ok_or_else(|| unknown("trigger", name, &self.triggers))?;
+        build(params).map_err(|err| format!("couldn't build trigger `{name}`: {err}").into())
+    }
+
+    pub(crate) fn data_value(&self, state: &StateData) -> Result<Box<dyn StateValue>> {
+        let (name, value) = match state {
+            StateData::Name(name) => (name, Value::Null),
+            StateData::Value { state, value } => (state, value.clone()),
+        };
+
+        let built = if let Some(construct) = self.constructors.get(name) {
+            construct(value)
+        } else if let Some(state) = self.states.get(name) {
+            (state.load)(value)
+        } else {
+            let mut names = self.states.keys().collect::<Vec<_>>();
+            names.extend(self.constructors.keys());
+            return Err(unknown_in("state", name, names).into());
+        };
+
+        built.map_err(|err| format!("couldn't build state `{name}`: {err}").into())
+    }
+
+    fn data_state(&self, name: &str) -> Result<&RegisteredState> {
+        Ok(self
+            .states
+            .get(name)
+            .ok_or_else(|| unknown("state", name, &self.states))?)
+    }
+
+    /// Builds an event, and finds the state that it's for. `None` stands for `AnyState`.
+    fn data_event(&self, event: &EventData) -> Result<(Option<&RegisteredState>, DataEvent)> {
+        let state = if event.state == ANY_STATE {
+            None
+        } else {
+            Some(self.data_state(&event.state)?)
+        };
+
+        let build = self
+            .events
+            .get(&event.event)
+            .ok_or_else(|| unknown("event", &event.event, &self.events))?;
+        let built = build(event.params.clone())
+            .map_err(|err| format!("couldn't build event `{}`: {err}", event.event))?;
+
+        Ok((state, built))
+    }
+}
+
+fn unknown<T>(kind: &str, name: &str, registered: &HashMap<String, T>) -> String {
+    unknown_in(kind, name, registered.keys().collect())
+}
+
+/// An error message for a name that isn't registered, listing the names that are
+fn unknown_in(kind: &str, name: &str, mut registered: Vec<&String>) -> String {
+    registered.sort();
+    let registered = registered
+        .iter()
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<_>>();
+
+    if registered.is_empty() {
+        format!("{kind} `{name}` isn't registered, and no {kind}s are registered")
+    } else {
+        format!(
+            "{kind} `{name}` isn't registered. The registered {kind}s are {}.",
+            registered.join(", "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        machine::transition,
+        registry::fixture::{self, Idle, Run, Walk},
+    };
+
+    use super::*;
+
+    #[derive(Resource)]
+    struct Go;
+
+    #[derive(Resource, Default)]
+    struct Entered(u32);
+
+    fn registry() -> MachineRegistry {
+        let mut registry = fixture::registry();
+        registry
+            .register_constructor("slow_walk", |_| Ok(Walk(1)))
+            .register_trigger("go", |_| Ok(|go: Option<Res<Go>>| go.is_some()))
+            .register_event("count", |params| {
+                let count = serde_json::from_value::<u32>(params)?;
+                Ok(move |entity: &mut EntityCommands| {
+                    entity.commands().queue(move |world: &mut World| {
+                        world.resource_mut::<Entered>().0 += count;
+                    });
+                })
+            });
+        registry
+    }
+
+    #[test]
+    fn test_build_data() {
+        let data = serde_json::from_str::<MachineData>(
+            r#"{
+                "transitions": [
+                    {"from": "idle", "to": "slow_walk", "trigger": "go"},
+                    {
+                        "from": ["idle", "walk"],
+                        "to": "run",
+                        "trigger": {"and": ["go", {"trigger": "frames", "params": 2}]}
+                    },
+                    {
+                        "from": "walk",
+                        "to": {"state": "walk", "value": 3},
+                        "trigger": {"not": "go"},
+                        "priority": 1
+                    }
+                ],
+                "on_enter": [{"state": "walk", "event": "count", "params": 1}]
+            }"#,
+        )
+        .unwrap();
+
+        let mut app = App::new();
+        app.init_resource::<Entered>()
+            .add_systems(Update, transition);
+
+        let machine = registry().build_data(&data).unwrap();
+        assert_eq!(machine.transitions().count(), 4);
+        // The prioritized transition comes first
+        let first = machine.transitions().next().unwrap();
+        assert_eq!(first.source.name(), std::any::type_name::<Walk>());
+        let entity = app.world_mut().spawn((machine, Idle)).id();
+
+        app.world_mut().insert_resource(Go);
+        app.update();
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(1)));
+        assert_eq!(app.world().resource::<Entered>().0, 1);
+
+        app.update();
+        app.update();
+        assert!(app.world().get::<Run>(entity).is_some());
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        let registry = registry();
+        let build = |data: &str| {
+            let data = serde_json::from_str::<MachineData>(data).unwrap();
+            registry.build_data(&data).err().unwrap().to_string()
+        };
+
+        assert!(
+            build(r#"{"transitions": [{"from": "idle", "to": "fly", "trigger": "go"}]}"#)
+                .contains("state `fly` isn't registered. The registered states are `idle`, ")
+        );
+        assert!(
+            build(r#"{"transitions": [{"from": "idle", "to": "run", "trigger": "jump"}]}"#)
+                .contains("in transition 0: trigger `jump` isn't registered")
+        );
+        assert!(build(r#"{"on_exit": [{"state": "*", "event": "shout"}]}"#)
+            .contains("in on-exit event 0: event `shout` isn't registered"));
+    }
+
+    #[test]
+    fn test_utility_data() {
+        let data = serde_json::from_str::<MachineData>(
+            r#"{
+                "transitions": [{"from": "idle", "to": "run", "trigger": "go"}],
+                "selection": {"Utility": {"threshold": 0.5, "hysteresis": 0}}
+            }"#,
+        )
+        .unwrap();
+
+        // The scores would be lost, so this would silently act like `Selection::First`
+        let err = registry().build_data(&data).err().unwrap().to_string();
+        assert!(err.contains("`Selection::Utility` can't be used in `MachineData`"));
+    }
+
+    #[test]
+    fn test_run_to_completion_data() {
+        let data = serde_json::from_str::<MachineData>(
+            r#"{
+                "transitions": [{"from": "idle", "to": "run", "trigger": "go"}],
+                "run_to_completion": 0
+            }"#,
+        )
+        .unwrap();
+
+        let err = registry().build_data(&data).err().unwrap().to_string();
+        assert!(err.contains("must be at least 1"));
+    }
+}
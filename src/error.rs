@@ -0,0 +1,191 @@
+//! Errors from state machines, and what to do about them. See [`MachineError`] and
+//! [`ErrorPolicy`].
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use bevy_ecs::entity::EntityHashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{prelude::*, Errors};
+
+/// An error from an entity's state machine. Written as a message whenever a machine fails, and
+/// handled by the machine's [`ErrorPolicy`].
+#[derive(Message, Clone, Debug, PartialEq)]
+pub enum MachineError {
+    /// The entity is in none of its machine's states
+    NoState {
+        /// The entity with the machine
+        entity: Entity,
+    },
+    /// The entity is in more than one of its machine's states
+    MultipleStates {
+        /// The entity with the machine
+        entity: Entity,
+        /// Two of the states that the entity is in
+        states: [&'static str; 2],
+    },
+    /// A trigger returned an error
+    Trigger {
+        /// The entity with the machine
+        entity: Entity,
+        /// The trigger's name. See `EntityTrigger::name`.
+        trigger: Cow<'static, str>,
+        /// The trigger's error
+        error: String,
+    },
+    /// A transition's builder or action returned an error
+    Transition {
+        /// The entity with the machine
+        entity: Entity,
+        /// The builder's or action's error
+        error: String,
+    },
+    /// A sub-machine was entered, or a machine was reset (see [`ErrorPolicy::Reset`]), but it has
+    /// no initial state
+    NoInitialState {
+        /// The entity with the machine
+        entity: Entity,
+    },
+    /// A machine that runs to completion was still transitioning after its maximum number of
+    /// iterations. See `StateMachine::run_to_completion`.
+    Loop {
+        /// The entity with the machine
+        entity: Entity,
+        /// The maximum number of iterations
+        iterations: u32,
+    },
+    /// Any other error, like from an on-enter or on-exit system, or an external change to the
+    /// entity's states (see `ExternalChanges::Error`)
+    Other {
+        /// The entity with the machine
+        entity: Entity,
+        /// The error
+        error: String,
+    },
+}
+
+impl MachineError {
+    /// The entity with the machine that failed
+    pub fn entity(&self) -> Entity {
+        match *self {
+            Self::NoState { entity }
+            | Self::MultipleStates { entity, .. }
+            | Self::Trigger { entity, .. }
+            | Self::Transition { entity, .. }
+            | Self::NoInitialState { entity }
+            | Self::Loop { entity, .. }
+            | Self::Other { entity, .. } => entity,
+        }
+    }
+}
+
+impl Display for MachineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoState { entity } => write!(f, "Entity {entity:?} is in no state"),
+            Self::MultipleStates {
+                entity,
+                states: [state, other],
+            } => write!(f, "{entity:?} is in multiple states: {state} and {other}"),
+            Self::Trigger {
+                entity,
+                trigger,
+                error,
+            } => write!(f, "{entity}'s trigger `{trigger}` failed: {error}"),
+            Self::Transition { entity, error } => {
+                write!(f, "{entity}'s transition failed: {error}")
+            }
+            Self::NoInitialState { entity } => {
+                write!(f, "State machine of {entity:?} has no initial state")
+            }
+            Self::Loop { entity, iterations } => write!(
+                f,
+                "{entity} was still transitioning after {iterations} iterations of its state \
+                machine. Do its transitions form a loop?"
+            ),
+            Self::Other { error, .. } => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for MachineError {}
+
+/// What happens to an entity whose state machine fails. Set it for every machine by inserting it
+/// as a resource (see `StateMachinePlugin::error_policy`), or for one machine with
+/// `StateMachine::with_error_policy`. Whatever the policy, every error is also written as a
+/// [`MachineError`] message.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ErrorPolicy {
+    /// Return the errors from the system that updates the machines, so they're given to Bevy's
+    /// error handler, every time they occur
+    #[default]
+    Report,
+    /// Log the errors once for each entity, until its machine runs without errors again
+    LogOnce,
+    /// Despawn the entity
+    Despawn,
+    /// Remove the entity's states, and put it in its machine's initial state (see
+    /// `StateMachine::initial_state`)
+    Reset,
+    /// Panic
+    Panic,
+}
+
+/// Errors collected while updating machines, grouped by entity, in the order they occurred
+#[derive(Default)]
+pub(crate) struct EntityErrs(EntityHashMap<Vec<MachineError>>, Vec<Entity>);
+
+impl EntityErrs {
+    /// Adds the entity's error, if `res` is one
+    pub(crate) fn push<T>(&mut self, entity: Entity, res: Result<T>) -> Option<T> {
+        match res {
+            Ok(t) => Some(t),
+            Err(err) => {
+                let Self(errs, order) = self;
+                let errs = errs.entry(entity).or_insert_with(|| {
+                    order.push(entity);
+                    Vec::new()
+                });
+                collect(entity, &err, errs);
+                None
+            }
+        }
+    }
+
+    /// Adds the entity's errors
+    pub(crate) fn extend(&mut self, entity: Entity, errs: impl IntoIterator<Item = BevyError>) {
+        for err in errs {
+            self.push::<()>(entity, Err(err));
+        }
+    }
+
+    /// Each entity that failed, with its errors
+    pub(crate) fn into_entities(self) -> impl Iterator<Item = (Entity, Vec<MachineError>)> {
+        let Self(mut errs, order) = self;
+        order
+            .into_iter()
+            .map(move |entity| (entity, errs.remove(&entity).unwrap_or_default()))
+    }
+}
+
+/// Finds the `MachineError`s in `err`, wrapping any other errors in `MachineError::Other`
+fn collect(entity: Entity, err: &BevyError, out: &mut Vec<MachineError>) {
+    if let Some(Errors(errs)) = err.downcast_ref::<Errors>() {
+        for err in errs {
+            collect(entity, err, out);
+        }
+    } else if let Some(err) = err.downcast_ref::<MachineError>() {
+        out.push(err.clone());
+    } else {
+        out.push(MachineError::Other {
+            entity,
+            error: err.to_string(),
+        });
+    }
+}
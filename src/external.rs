@@ -0,0 +1,135 @@
+//! Recording changes to entities' states that weren't made by their state machines. See
+//! `ExternalChanges`.
+
+use std::{any::TypeId, collections::HashSet};
+
+use bevy_ecs::entity::EntityHashMap;
+
+use crate::{prelude::*, state::StateValue};
+
+/// A change to an entity's states that wasn't made by its state machine
+pub(crate) enum Record {
+    /// A state was removed or replaced. Holds its value from before the change.
+    Left(Box<dyn StateValue>),
+    /// A state was inserted
+    Entered(TypeId),
+}
+
+impl Record {
+    pub(crate) fn state_id(&self) -> TypeId {
+        match self {
+            Record::Left(value) => value.state_id(),
+            Record::Entered(state) => *state,
+        }
+    }
+}
+
+/// Changes to entities' states that weren't made by their state machines, in order, to be handled
+/// the next time the machines transition
+#[derive(Resource, Default)]
+pub(crate) struct ExternalRecords {
+    entities: EntityHashMap<Vec<Record>>,
+    /// Set while a state machine changes states itself, so its changes aren't recorded
+    paused: bool,
+    /// The state types that are observed
+    observed: HashSet<TypeId>,
+}
+
+/// Takes the changes recorded since the last call
+pub(crate) fn take_records(world: &mut World) -> EntityHashMap<Vec<Record>> {
+    world
+        .get_resource_mut::<ExternalRecords>()
+        .map(|mut records| std::mem::take(&mut records.entities))
+        .unwrap_or_default()
+}
+
+/// Runs `f` without recording state changes, since it's a state machine making them. Commands
+/// queued by `f` are applied before recording resumes.
+pub(crate) fn paused<T>(world: &mut World, f: impl FnOnce(&mut World) -> T) -> T {
+    // The machine may start observing its states in `f`, so the records must exist beforehand
+    let mut records = world.get_resource_or_init::<ExternalRecords>();
+    let was_paused = std::mem::replace(&mut records.paused, true);
+    let out = f(world);
+
+    // Commands queued by the machine's events are part of its changes too
+    if !was_paused {
+        world.flush();
+    }
+
+    if let Some(mut records) = world.get_resource_mut::<ExternalRecords>() {
+        records.paused = was_paused;
+    }
+
+    out
+}
+
+/// Starts recording changes to `S` on entities with state machines, if they aren't already
+pub(crate) fn observe<S: Clone + Component>(world: &mut World) {
+    let mut records = world.get_resource_or_init::<ExternalRecords>();
+    if !records.observed.insert(TypeId::of::<S>()) {
+        return;
+    }
+
+    world.add_observer(record_insert::<S>);
+    world.add_observer(record_replace::<S>);
+}
+
+fn record_insert<S: Component>(
+    insert: On<Insert, S>,
+    mut records: ResMut<ExternalRecords>,
+    machines: Query<MachineQuery>,
+) {
+    let state = TypeId::of::<S>();
+    if records.paused
+        || !machines
+            .get(insert.entity)
+            .is_ok_and(|machine| watches(machine, state))
+    {
+        return;
+    }
+
+    records
+        .entities
+        .entry(insert.entity)
+        .or_default()
+        .push(Record::Entered(state));
+}
+
+fn record_replace<S: Clone + Component>(
+    replace: On<Replace, S>,
+    mut records: ResMut<ExternalRecords>,
+    states: Query<(&S, MachineQuery)>,
+) {
+    if records.paused {
+        return;
+    }
+
+    let Ok((state, machine)) = states.get(replace.entity) else {
+        return;
+    };
+    if !watches(machine, TypeId::of::<S>()) {
+        return;
+    }
+
+    records
+        .entities
+        .entry(replace.entity)
+        .or_default()
+        .push(Record::Left(Box::new(state.clone())));
+}
+
+type MachineQuery = AnyOf<(&'static StateMachine, &'static StateMachineDef)>;
+
+/// Whether the entity's machine handles external changes to the state. Machines that ignore them
+/// aren't recorded, since nothing would read the records.
+fn watches(
+    (machine, def): (Option<&StateMachine>, Option<&StateMachineDef>),
+    state: TypeId,
+) -> bool {
+    match (machine, def) {
+        (Some(machine), _) => machine.watches(state),
+        // The definition is only locked while it's running, when changes aren't recorded anyway
+        (None, Some(def)) => def.machine().map_or(true, |machine| machine.watches(state)),
+        (None, None) => false,
+    }
+}
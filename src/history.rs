@@ -0,0 +1,236 @@
+//! State history, so that machines can return to the states they were in before. See
+//! [`StateHistory`] and `StateMachine::trans_history`.
+
+use std::{any::TypeId, collections::VecDeque};
+
+use crate::{prelude::*, state::StateValue};
+
+/// How a history transition restores a state. The `usize` is how far back in the history to go;
+/// `0` is the most recent state that the machine was in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum History {
+    /// Restores the state. If it has a sub-machine, the sub-machine enters its initial state.
+    Shallow(usize),
+    /// Restores the state. If it has a sub-machine, the sub-machine restores the state that it was
+    /// in when the state was exited, and so on for its sub-machines.
+    Deep(usize),
+}
+
+impl History {
+    /// How far back in the history to go
+    pub fn back(self) -> usize {
+        match self {
+            Self::Shallow(back) | Self::Deep(back) => back,
+        }
+    }
+}
+
+/// A state that was exited, and the states that its sub-machine and regions were in at the time
+#[derive(Debug)]
+pub(crate) struct HistoryEntry {
+    pub(crate) value: Box<dyn StateValue>,
+    pub(crate) sub: Vec<HistoryEntry>,
+}
+
+impl Clone for HistoryEntry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone_value(),
+            sub: self.sub.clone(),
+        }
+    }
+}
+
+/// Records the states that an entity has left, so that history transitions (see
+/// `StateMachine::trans_history`) can return to them. Add this to an entity with a state machine to
+/// record its history. Only the most recent `capacity` states are kept.
+#[derive(Component, Debug, Clone)]
+pub struct StateHistory {
+    capacity: usize,
+    /// Least recent first
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl StateHistory {
+    /// Creates an empty history that keeps up to `capacity` states
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// How many states are kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many states have been recorded
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no states have been recorded
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The `TypeId`s of the recorded states, most recent first
+    pub fn states(&self) -> impl Iterator<Item = TypeId> + '_ {
+        self.entries
+            .iter()
+            .rev()
+            .map(|entry| entry.value.state_id())
+    }
+
+    /// Forgets every recorded state
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The recorded entries, least recent first
+    #[cfg(feature = "serde")]
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub(crate) fn push(&mut self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    /// Finds the entry `back` entries back, only counting states that match `filter`
+    pub(crate) fn find(
+        &self,
+        back: usize,
+        filter: impl Fn(TypeId) -> bool,
+    ) -> Option<&HistoryEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| filter(entry.value.state_id()))
+            .nth(back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::transition;
+
+    use super::*;
+
+    #[derive(Component, Clone)]
+    struct Idle;
+    #[derive(Component, Clone, Debug, PartialEq)]
+    struct Walk(u32);
+    #[derive(Component, Clone)]
+    struct Stunned;
+    #[derive(Component, Clone)]
+    struct Combat;
+    #[derive(Component, Clone)]
+    struct Approach;
+    #[derive(Component, Clone)]
+    struct Strafe;
+
+    #[derive(Resource)]
+    struct Stun;
+
+    fn stun(stun: Option<Res<Stun>>) -> bool {
+        stun.is_some()
+    }
+
+    #[test]
+    fn test_history() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(always, Walk(3))
+            .trans::<NotState<Stunned>, _>(stun, Stunned)
+            .trans_history::<Stunned, _>(stun.not(), History::Shallow(0));
+
+        let entity = app
+            .world_mut()
+            .spawn((machine, Idle, StateHistory::new(4)))
+            .id();
+
+        app.update();
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(3)));
+
+        app.world_mut().insert_resource(Stun);
+        app.update();
+        assert!(app.world().get::<Stunned>(entity).is_some());
+
+        app.world_mut().remove_resource::<Stun>();
+        app.update();
+        // the previous state's value is restored
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(3)));
+        assert!(app.world().get::<Stunned>(entity).is_none());
+
+        let history = app.world().get::<StateHistory>(entity).unwrap();
+        assert_eq!(
+            history.states().collect::<Vec<_>>(),
+            [
+                TypeId::of::<Stunned>(),
+                TypeId::of::<Walk>(),
+                TypeId::of::<Idle>()
+            ],
+        );
+    }
+
+    #[test]
+    fn test_deep_history() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let combat = StateMachine::default().trans::<Approach, _>(always, Strafe);
+
+        let machine = StateMachine::default()
+            .trans::<Combat, _>(stun, Stunned)
+            .trans_history::<Stunned, _>(stun.not(), History::Deep(0))
+            .sub_machine::<Combat>(Approach, combat);
+
+        let entity = app
+            .world_mut()
+            .spawn((machine, Combat, StateHistory::new(4)))
+            .id();
+
+        app.update();
+        app.update();
+        assert!(app.world().get::<Strafe>(entity).is_some());
+
+        app.world_mut().insert_resource(Stun);
+        app.update();
+        assert!(app.world().get::<Stunned>(entity).is_some());
+        assert!(app.world().get::<Strafe>(entity).is_none());
+
+        app.world_mut().remove_resource::<Stun>();
+        app.update();
+        // the sub-machine's state is restored too
+        assert!(app.world().get::<Combat>(entity).is_some());
+        assert!(app.world().get::<Strafe>(entity).is_some());
+        assert!(app.world().get::<Approach>(entity).is_none());
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let mut history = StateHistory::new(2);
+
+        for i in 0..3 {
+            history.push(HistoryEntry {
+                value: Box::new(Walk(i)),
+                sub: Vec::new(),
+            });
+        }
+
+        assert_eq!(history.len(), 2);
+        assert!(history.find(2, |_| true).is_none());
+    }
+}
@@ -0,0 +1,496 @@
+//! Read-only views of a [`StateMachine`]'s states, transitions, and events, for tools and debugging.
+//! See `StateMachine::states` and `StateMachine::transitions`.
+
+use std::{
+    any::{type_name, TypeId},
+    borrow::Cow,
+    fmt::{self, Debug, Formatter},
+};
+
+use bevy_utils::TypeIdMap;
+
+use crate::{history::History, prelude::*};
+
+/// A state registered in a [`StateMachine`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StateInfo {
+    /// The state's `TypeId`
+    pub id: TypeId,
+    /// The state's type name
+    pub name: &'static str,
+}
+
+/// The states that a transition or event applies to, given as a type parameter like `Idle`,
+/// `AnyState`, `NotState<Idle>`, or `OneOfState<(Idle, Walk)>`
+#[derive(Clone, Copy)]
+pub struct StateMatcher {
+    name: &'static str,
+    matches: fn(TypeId) -> bool,
+}
+
+impl StateMatcher {
+    pub(crate) fn of<S: EntityState>() -> Self {
+        Self {
+            name: type_name::<S>(),
+            matches: S::matches,
+        }
+    }
+
+    /// The matcher's type name
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether the given state matches
+    pub fn matches(&self, state: TypeId) -> bool {
+        (self.matches)(state)
+    }
+}
+
+impl Debug for StateMatcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StateMatcher").field(&self.name).finish()
+    }
+}
+
+/// A transition in a [`StateMachine`]
+#[derive(Clone, Debug)]
+pub struct TransitionInfo {
+    /// The states that this transition may be taken from
+    pub source: StateMatcher,
+    /// Where this transition goes
+    pub target: TransitionTarget,
+    /// The trigger's name. See `EntityTrigger::name`.
+    pub trigger: Cow<'static, str>,
+    /// See `StateMachine::with_priority`
+    pub priority: i32,
+    /// See `StateMachine::with_weight`
+    pub weight: f32,
+}
+
+/// Where a transition goes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionTarget {
+    /// A state of the machine
+    State(StateInfo),
+    /// A state from the entity's `StateHistory`. See `StateMachine::trans_history`.
+    History(History),
+}
+
+/// An on-enter or on-exit event in a [`StateMachine`]
+#[derive(Clone, Copy, Debug)]
+pub struct EventInfo {
+    /// The states that the machine must be leaving for the event to run
+    pub from: StateMatcher,
+    /// The states that the machine must be entering for the event to run
+    pub to: StateMatcher,
+    /// What the event runs
+    pub kind: EventKind,
+}
+
+/// What an on-enter or on-exit event runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// A closure over `EntityCommands`, added with `StateMachine::on_enter` and the like
+    Entity,
+    /// A `Command`, added with `StateMachine::command_on_enter` and the like
+    Command,
+    /// A system, added with `StateMachine::system_on_enter` and the like
+    System,
+}
+
+impl StateMachine {
+    /// Renders this machine as a Graphviz DOT graph. Each transition is drawn as an edge from every
+    /// concrete state that it may be taken from, labeled with its trigger's name and its priority,
+    /// and its weight if it isn't 1. Sub-machines and regions are drawn as clusters, and history transitions
+    /// go to an `H` (shallow) or `H*` (deep) node.
+    pub fn to_dot(&self) -> String {
+        let graph = GraphBuilder::default().build(self);
+        let mut dot = String::from("digraph {\n");
+        let mut edges = Vec::new();
+        let mut ids = 0;
+        write_dot(&graph, 1, &mut ids, &mut dot, &mut edges);
+
+        for Edge { from, to, label } in edges {
+            dot += &format!("    {from} -> {to}");
+            if !label.is_empty() {
+                dot += &format!(" [label=\"{}\"]", dot_escape(&label));
+            }
+            dot += ";\n";
+        }
+
+        dot + "}\n"
+    }
+
+    /// Renders this machine as a Mermaid state diagram. See [`StateMachine::to_dot`]. Sub-machines
+    /// are drawn as composite states, with their regions separated by `--`. Other regions, like
+    /// this machine's, are drawn as composite states of their own.
+    pub fn to_mermaid(&self) -> String {
+        let graph = GraphBuilder::default().build(self);
+        let mut mermaid = String::from("stateDiagram-v2\n");
+        let mut ids = 0;
+        write_mermaid(&graph, 1, &mut ids, &mut mermaid);
+        mermaid
+    }
+}
+
+/// A machine, ready to render
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    initial: Option<String>,
+    regions: Vec<Graph>,
+}
+
+struct Node {
+    id: String,
+    label: String,
+    history: bool,
+    sub_machine: Option<Graph>,
+}
+
+#[derive(Clone)]
+struct Edge {
+    from: String,
+    to: String,
+    label: String,
+}
+
+/// Assigns node IDs. States may not be shared between a machine, its sub-machines, and its
+/// regions, so each state gets one ID for the whole graph.
+#[derive(Default)]
+struct GraphBuilder {
+    ids: TypeIdMap<String>,
+    histories: usize,
+}
+
+impl GraphBuilder {
+    fn state_id(&mut self, state: TypeId) -> String {
+        let next = self.ids.len();
+        self.ids
+            .entry(state)
+            .or_insert_with(|| format!("s{next}"))
+            .clone()
+    }
+
+    fn build(&mut self, machine: &StateMachine) -> Graph {
+        let states = machine.states();
+        let mut graph = Graph {
+            initial: machine.initial_state().map(|state| self.state_id(state.id)),
+            ..default()
+        };
+
+        for state in &states {
+            let node = Node {
+                id: self.state_id(state.id),
+                label: short_name(state.name),
+                history: false,
+                sub_machine: machine
+                    .get_sub_machine(state.id)
+                    .map(|sub_machine| self.build(sub_machine)),
+            };
+            graph.nodes.push(node);
+        }
+
+        let mut history_ids = Vec::<(History, String)>::new();
+        for transition in machine.transitions() {
+            let to = match transition.target {
+                TransitionTarget::State(state) => self.state_id(state.id),
+                TransitionTarget::History(history) => {
+                    match history_ids.iter().find(|(other, _)| *other == history) {
+                        Some((_, id)) => id.clone(),
+                        None => {
+                            let id = format!("h{}", self.histories);
+                            self.histories += 1;
+                            history_ids.push((history, id.clone()));
+                            graph.nodes.push(Node {
+                                id: id.clone(),
+                                label: history_label(history),
+                                history: true,
+                                sub_machine: None,
+                            });
+                            id
+                        }
+                    }
+                }
+            };
+
+            let label = edge_label(&transition);
+            for state in states
+                .iter()
+                .filter(|state| transition.source.matches(state.id))
+            {
+                graph.edges.push(Edge {
+                    from: self.state_id(state.id),
+                    to: to.clone(),
+                    label: label.clone(),
+                });
+            }
+        }
+
+        graph.regions = machine
+            .regions()
+            .iter()
+            .map(|region| self.build(region))
+            .collect();
+        graph
+    }
+}
+
+/// The trigger's name, with the transition's priority, and its weight if it isn't the default
+fn edge_label(transition: &TransitionInfo) -> String {
+    let trigger = short_name(&transition.trigger);
+    match transition.weight {
+        1. => format!("{trigger} (priority {})", transition.priority),
+        weight => format!(
+            "{trigger} (priority {}, weight {weight})",
+            transition.priority
+        ),
+    }
+}
+
+fn history_label(history: History) -> String {
+    match history {
+        History::Shallow(0) => "H".to_string(),
+        History::Deep(0) => "H*".to_string(),
+        History::Shallow(back) => format!("H ({back} back)"),
+        History::Deep(back) => format!("H* ({back} back)"),
+    }
+}
+
+/// Strips module paths from a type name, so `seldom_state::state::NotState<game::Idle>` becomes
+/// `NotState<Idle>`. Closures keep the name of the function they're in.
+fn short_name(name: &str) -> String {
+    fn shorten(path: &str) -> &str {
+        let mut segments = path.rsplit("::");
+        let last = segments.next().unwrap_or_default();
+        match segments.next() {
+            Some(parent) if last.starts_with('{') => {
+                &path[path.len() - parent.len() - 2 - last.len()..]
+            }
+            _ => last,
+        }
+    }
+
+    let mut short = String::with_capacity(name.len());
+    let mut path_start = 0;
+    for (index, c) in name.char_indices() {
+        if matches!(c, '<' | '>' | '(' | ')' | ',' | '[' | ']' | '&' | ';' | ' ') {
+            short += shorten(&name[path_start..index]);
+            short.push(c);
+            path_start = index + c.len_utf8();
+        }
+    }
+
+    short + shorten(&name[path_start..])
+}
+
+fn write_dot(
+    graph: &Graph,
+    depth: usize,
+    ids: &mut usize,
+    dot: &mut String,
+    edges: &mut Vec<Edge>,
+) {
+    let indent = "    ".repeat(depth);
+
+    if let Some(initial) = &graph.initial {
+        let id = format!("i{ids}");
+        *ids += 1;
+        *dot += &format!("{indent}{id} [shape=point];\n");
+        edges.push(Edge {
+            from: id,
+            to: initial.clone(),
+            label: String::new(),
+        });
+    }
+
+    for node in &graph.nodes {
+        let label = dot_escape(&node.label);
+        let shape = if node.history { "circle" } else { "box" };
+        let line = format!("{id} [label=\"{label}\", shape={shape}];\n", id = node.id);
+
+        match &node.sub_machine {
+            Some(sub_machine) => {
+                *dot += &format!("{indent}subgraph cluster_{} {{\n", node.id);
+                *dot += &format!("{indent}    label=\"{label}\";\n");
+                *dot += &format!("{indent}    {line}");
+                write_dot(sub_machine, depth + 1, ids, dot, edges);
+                *dot += &format!("{indent}}}\n");
+            }
+            None => *dot += &format!("{indent}{line}"),
+        }
+    }
+
+    edges.extend(graph.edges.iter().cloned());
+
+    for (index, region) in graph.regions.iter().enumerate() {
+        *dot += &format!("{indent}subgraph cluster_r{ids} {{\n");
+        *ids += 1;
+        *dot += &format!("{indent}    label=\"region {}\";\n", index + 1);
+        write_dot(region, depth + 1, ids, dot, edges);
+        *dot += &format!("{indent}}}\n");
+    }
+}
+
+/// Writes the machine's states and transitions, and its regions as composite states
+fn write_mermaid(graph: &Graph, depth: usize, ids: &mut usize, mermaid: &mut String) {
+    let indent = "    ".repeat(depth);
+    write_mermaid_body(graph, depth, ids, mermaid);
+
+    for (index, region) in graph.regions.iter().enumerate() {
+        let id = format!("r{ids}");
+        *ids += 1;
+        *mermaid += &format!("{indent}state \"region {}\" as {id}\n", index + 1);
+        *mermaid += &format!("{indent}state {id} {{\n");
+        write_mermaid(region, depth + 1, ids, mermaid);
+        *mermaid += &format!("{indent}}}\n");
+    }
+}
+
+/// Writes the machine's states and transitions, without its regions
+fn write_mermaid_body(graph: &Graph, depth: usize, ids: &mut usize, mermaid: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    if let Some(initial) = &graph.initial {
+        *mermaid += &format!("{indent}[*] --> {initial}\n");
+    }
+
+    for node in &graph.nodes {
+        let label = mermaid_escape(&node.label);
+        *mermaid += &format!("{indent}state \"{label}\" as {}\n", node.id);
+
+        if let Some(sub_machine) = &node.sub_machine {
+            *mermaid += &format!("{indent}state {} {{\n", node.id);
+            let own = !sub_machine.nodes.is_empty();
+            if own {
+                write_mermaid_body(sub_machine, depth + 1, ids, mermaid);
+            }
+
+            for (index, region) in sub_machine.regions.iter().enumerate() {
+                if own || index > 0 {
+                    *mermaid += &format!("{indent}    --\n");
+                }
+                write_mermaid(region, depth + 1, ids, mermaid);
+            }
+            *mermaid += &format!("{indent}}}\n");
+        }
+    }
+
+    for Edge { from, to, label } in &graph.edges {
+        *mermaid += &format!("{indent}{from} --> {to}: {}\n", mermaid_escape(label));
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Mermaid gives special meaning to some characters in labels, so these are written as entity
+/// codes
+fn mermaid_escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' | ':' | ';' | '{' | '}' | '<' | '>' | '#' => format!("#{};", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Component, Clone)]
+    struct Idle;
+    #[derive(Component, Clone)]
+    struct Walk;
+    #[derive(Component, Clone)]
+    struct Stunned;
+    #[derive(Component, Clone)]
+    struct Approach;
+    #[derive(Component, Clone)]
+    struct Strafe;
+
+    fn tired() -> bool {
+        false
+    }
+
+    fn machine() -> StateMachine {
+        StateMachine::default()
+            .trans::<Idle, _>(always, Walk)
+            .trans::<NotState<Stunned>, _>(tired, Stunned)
+            .trans_history::<Stunned, _>(always, History::Shallow(0))
+            .sub_machine::<Walk>(
+                Approach,
+                StateMachine::default().trans::<Approach, _>(always, Strafe),
+            )
+    }
+
+    #[test]
+    fn test_short_name() {
+        assert_eq!(
+            short_name("seldom_state::state::NotState<game::ai::Idle>"),
+            "NotState<Idle>",
+        );
+        assert_eq!(
+            short_name("not(game::ai::tired::{{closure}})"),
+            "not(tired::{{closure}})",
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = machine().to_dot();
+
+        // `NotState<Stunned>` is expanded to `Idle` and `Walk`
+        let walk = dot
+            .lines()
+            .find(|line| line.contains("label=\"Walk\", shape=box"));
+        let walk = walk.unwrap().trim().split(' ').next().unwrap();
+        assert!(dot.contains(&format!("-> {walk} [label=\"always (priority 0)\"]")));
+        assert_eq!(dot.matches("[label=\"tired (priority 0)\"]").count(), 2);
+        assert!(dot.contains(&format!("subgraph cluster_{walk} {{")));
+        assert!(dot.contains("[label=\"H\", shape=circle]"));
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let mermaid = machine().to_mermaid();
+
+        assert!(mermaid.starts_with("stateDiagram-v2\n"));
+        assert_eq!(mermaid.matches(": tired (priority 0)").count(), 2);
+        assert!(mermaid.contains("state \"Approach\" as"));
+        assert!(mermaid.contains("[*] -->"));
+    }
+
+    #[test]
+    fn test_priority_labels() {
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(always, Walk)
+            .trans::<Idle, _>(tired, Stunned)
+            .with_priority(2)
+            .with_weight(0.5);
+
+        // The edges show the priorities, rather than the order they're checked in
+        let dot = machine.to_dot();
+        assert!(dot.contains("[label=\"always (priority 0)\"]"));
+        assert!(dot.contains("[label=\"tired (priority 2, weight 0.5)\"]"));
+    }
+
+    #[test]
+    fn test_nested_regions() {
+        let machine = StateMachine::default().region(
+            StateMachine::default()
+                .trans::<Idle, _>(always, Walk)
+                .region(StateMachine::default().trans::<Approach, _>(always, Strafe)),
+        );
+
+        // The region inside the region is drawn, like `to_dot` draws it
+        let mermaid = machine.to_mermaid();
+        assert!(mermaid.contains("state \"Strafe\" as"));
+        assert_eq!(mermaid.matches("state \"region 1\" as").count(), 2);
+        assert!(machine.to_dot().contains("label=\"Strafe\""));
+    }
+}
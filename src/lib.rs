@@ -4,25 +4,50 @@
 #![warn(missing_docs)]
 #![allow(clippy::type_complexity)]
 
+#[cfg(feature = "asset")]
+pub mod asset;
+#[cfg(feature = "serde")]
+pub mod data;
+pub mod error;
+mod external;
+pub mod history;
+pub mod introspect;
 pub mod machine;
+#[cfg(feature = "reflect")]
+pub mod reflect;
+#[cfg(feature = "serde")]
+pub mod registry;
+#[cfg(feature = "serde")]
+pub mod save;
 pub mod set;
 mod state;
+pub mod timer;
 pub mod trigger;
 
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
 use bevy_derive::{Deref, DerefMut};
 use bevy_ecs::{intern::Interned, schedule::ScheduleLabel};
+use error::{ErrorPolicy, MachineError};
 use prelude::*;
 
 /// Add to your app to use this crate
 #[derive(Debug)]
 pub struct StateMachinePlugin {
     schedule: Interned<dyn ScheduleLabel>,
+    transition_messages: bool,
+    error_policy: ErrorPolicy,
 }
 
 impl Default for StateMachinePlugin {
     fn default() -> Self {
         Self {
             schedule: PostUpdate.intern(),
+            transition_messages: false,
+            error_policy: default(),
         }
     }
 }
@@ -33,11 +58,47 @@ impl StateMachinePlugin {
         self.schedule = schedule.intern();
         self
     }
+
+    /// Sets whether a `Transitioned` message is written for every transition, and a `Completed`
+    /// message whenever a machine reaches a final state, in addition to the events. Defaults to
+    /// `false`.
+    pub fn transition_messages(mut self, transition_messages: bool) -> Self {
+        self.transition_messages = transition_messages;
+        self
+    }
+
+    /// Sets what happens to entities whose state machines fail, unless their machines have their
+    /// own policy (see `StateMachine::with_error_policy`). Defaults to `ErrorPolicy::Report`.
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
 }
 
 impl Plugin for StateMachinePlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((machine::plug(self.schedule), trigger::plug(self.schedule)));
+        app.add_plugins((
+            machine::plug(self.schedule),
+            timer::plug(self.schedule),
+            trigger::plug(self.schedule),
+        ));
+
+        #[cfg(feature = "reflect")]
+        app.add_plugins(reflect::plug(self.schedule));
+
+        app.insert_resource(self.error_policy)
+            .add_message::<MachineError>();
+
+        if self.transition_messages {
+            app.add_message::<machine::Transitioned>()
+                .add_message::<machine::Completed>();
+        }
+    }
+
+    #[cfg(feature = "asset")]
+    fn finish(&self, app: &mut App) {
+        // `AssetPlugin` may be added after this plugin
+        asset::plug(app);
     }
 }
 
@@ -52,6 +113,10 @@ pub mod prelude {
     #[cfg(feature = "leafwing_input")]
     pub(crate) use leafwing_input_manager::prelude::*;
 
+    #[cfg(feature = "asset")]
+    pub use crate::asset::{MachineAsset, StateMachineAsset};
+    #[cfg(feature = "reflect")]
+    pub use crate::reflect::StateMachineInfo;
     #[cfg(feature = "leafwing_input")]
     pub use crate::trigger::{
         action_data, axis_pair, axis_pair_length_bounds, axis_pair_max_length,
@@ -62,10 +127,21 @@ pub mod prelude {
         clamped_value_unbounded, just_pressed, just_released, pressed, value, value_max, value_min,
         value_unbounded,
     };
+    #[cfg(feature = "serde")]
+    pub use crate::{data::MachineData, registry::MachineRegistry};
     pub use crate::{
-        machine::{StateMachine, Trans},
-        state::{AnyState, EntityState, NotState, OneOfState},
-        trigger::{always, done, on_message, Done, EntityTrigger, IntoTrigger, Never},
+        error::{ErrorPolicy, MachineError},
+        history::{History, StateHistory},
+        machine::{
+            Completed, CurrentState, ExternalChanges, Selection, StateMachine, StateMachineDef,
+            Trans, TransRef, TransitionCommands, TransitionRng, Transitioned,
+        },
+        state::{AnyState, EntityState, EventIn, NotState, OneOfState, StateChange},
+        timer::StateTimer,
+        trigger::{
+            always, done, frames_in_state, on_message, random_time_in_state, time_in_state, Done,
+            EntityTrigger, IntoTrigger, Never, Score,
+        },
         StateMachinePlugin,
     };
 }
@@ -88,16 +164,26 @@ impl ErrList {
 }
 
 impl From<ErrList> for Result {
-    fn from(value: ErrList) -> Self {
-        if value.is_empty() {
-            OK
-        } else {
-            Err(value
-                .iter()
-                .map(BevyError::to_string)
-                .collect::<Vec<_>>()
-                .join("; ")
-                .into())
+    fn from(ErrList(mut errs): ErrList) -> Self {
+        match errs.len() {
+            0 => OK,
+            // Keep the error as it is, so it can be downcast
+            1 => Err(errs.pop().unwrap()),
+            _ => Err(Errors(errs).into()),
         }
     }
 }
+
+/// Several errors, displayed together. Kept separate, so each can be downcast.
+#[derive(Debug)]
+struct Errors(Vec<BevyError>);
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self(errs) = self;
+        let errs = errs.iter().map(BevyError::to_string).collect::<Vec<_>>();
+        write!(f, "{}", errs.join("; "))
+    }
+}
+
+impl Error for Errors {}
@@ -2,126 +2,279 @@
 
 use std::{
     any::{type_name, Any, TypeId},
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
     fmt::Debug,
     marker::PhantomData,
+    ops::Deref,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError,
+    },
+    time::Duration,
 };
 
-use bevy_ecs::{intern::Interned, schedule::ScheduleLabel};
+#[cfg(feature = "asset")]
+use std::collections::HashSet;
+
+use bevy_ecs::{
+    entity::{EntityHashMap, EntityHashSet},
+    intern::Interned,
+    lifecycle::HookContext,
+    schedule::ScheduleLabel,
+    system::{BoxedSystem, InRef},
+    world::DeferredWorld,
+};
+use bevy_tasks::{ComputeTaskPool, ParallelSliceMut, TaskPool};
 use bevy_utils::TypeIdMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    error::{EntityErrs, ErrorPolicy, MachineError},
+    external::{self, Record},
+    history::{History, HistoryEntry, StateHistory},
+    introspect::{EventInfo, EventKind, StateInfo, StateMatcher, TransitionInfo, TransitionTarget},
     prelude::*,
     set::StateSet,
-    state::OnEvent,
-    trigger::{IntoTrigger, TriggerOut},
+    state::{EventIn, NextState, OnEvent, StateChange, StateValue, UpdateSystem},
+    timer::StateTimer,
+    trigger::{IntoTrigger, KeepDone, TriggerIn, TriggerOut},
     ErrList, OK,
 };
 
 pub(crate) fn plug(schedule: Interned<dyn ScheduleLabel>) -> impl Fn(&mut App) {
     move |app| {
-        app.add_systems(schedule, transition.in_set(StateSet::Transition));
+        app.init_resource::<TransitionRng>()
+            .configure_sets(schedule, StateSet::OnUpdate.before(StateSet::Transition))
+            .add_systems(schedule, on_update.in_set(StateSet::OnUpdate))
+            .add_systems(schedule, transition.in_set(StateSet::Transition));
+    }
+}
+
+/// Checks a transition's trigger. We have a trait for this so we can erase the trigger's type.
+/// Triggers are kept apart from their transitions, so that each entity that uses a
+/// [`StateMachineDef`] has its own.
+trait TransitionTrigger: Send + Sync + 'static {
+    /// Called before any call to `check`, and whenever the trigger's machine transitions
+    fn init(&mut self, world: &mut World);
+    /// Checks whether the transition should be taken. `entity` is the entity that contains the
+    /// state machine. If it should, returns the trigger's output, to be given to `take`.
+    fn check(&mut self, world: &World, entity: Entity) -> Result<Option<Box<dyn Any + Send>>>;
+    /// Saves the trigger's data. See `EntityTrigger::save`.
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value>;
+    /// Loads the trigger's data. See `EntityTrigger::load`.
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result;
+}
+
+impl<T: EntityTrigger> TransitionTrigger for T {
+    fn init(&mut self, world: &mut World) {
+        EntityTrigger::init(self, world);
+    }
+
+    fn check(&mut self, world: &World, entity: Entity) -> Result<Option<Box<dyn Any + Send>>> {
+        Ok(EntityTrigger::check(self, entity, world)?
+            .into_result()
+            .ok()
+            .map(|out| Box::new(out) as Box<dyn Any + Send>))
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        EntityTrigger::save(self, world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        EntityTrigger::load(self, data, world)
     }
 }
 
+/// The triggers of a machine, in the order that its transitions were added, along with its
+/// sub-machines' and regions' triggers
+#[derive(Default)]
+struct Triggers {
+    triggers: Vec<Box<dyn TransitionTrigger>>,
+    /// Whether the triggers have never been initialized
+    init: bool,
+    /// Keyed by the state that each sub-machine is nested in
+    sub_machines: TypeIdMap<Triggers>,
+    regions: Vec<Triggers>,
+}
+
 /// Performs a transition. We have a trait for this so we can erase [`TransitionImpl`]'s generics.
 trait Transition: Debug + Send + Sync + 'static {
-    /// Called before any call to `check`
+    /// Called before any call to `take`
     fn init(&mut self, world: &mut World);
-    /// Checks whether the transition should be taken. `entity` is the entity that contains the
-    /// state machine.
-    fn check<'a>(
-        &'a mut self,
-        world: &World,
+    /// The state that this transition goes to
+    fn target(&self) -> Target;
+    /// Sets the action, which must be a boxed [`Action`] with this transition's types. See
+    /// [`StateMachine::with_action`].
+    fn set_action(&mut self, action: Box<dyn Any>);
+    /// Takes the transition. `curr` is the entity's current state, and `out` is the output from
+    /// the trigger's `check`.
+    fn take(
+        &mut self,
+        world: &mut World,
         entity: Entity,
-    ) -> Result<Option<(Box<dyn 'a + FnOnce(&mut World, TypeId) -> Result>, TypeId)>>;
+        curr: TypeId,
+        out: Box<dyn Any + Send>,
+    ) -> Result;
 }
 
-/// An edge in the state machine. The type parameters are the [`EntityTrigger`] that causes this
-/// transition, the previous state, the function that takes the trigger's output and builds the next
-/// state, and the next state itself.
-struct TransitionImpl<Trig, Prev, Build, Next>
+/// An edge in the state machine. The type parameters are the previous state, the output of the
+/// [`EntityTrigger`] that causes this transition, the function that takes the trigger's output and
+/// builds the next state, and the next state itself.
+struct TransitionImpl<Prev, Out, Build, Next>
 where
-    Trig: EntityTrigger,
     Prev: EntityState,
-    Build: System<In = Trans<Prev, <Trig::Out as TriggerOut>::Ok>, Out = Next>,
-    Next: Component + EntityState,
+    Out: 'static,
+    Build: System<In = Trans<Prev, Out>, Out = Next>,
+    Next: NextState,
 {
-    trigger: Trig,
     builder: Build,
+    action: Option<Action<Prev, Out>>,
+    /// The state that `Next` enters
+    target: TypeId,
     phantom: PhantomData<Prev>,
 }
 
-impl<Trig, Prev, Build, Next> Debug for TransitionImpl<Trig, Prev, Build, Next>
+impl<Prev, Out, Build, Next> Debug for TransitionImpl<Prev, Out, Build, Next>
 where
-    Trig: EntityTrigger,
     Prev: EntityState,
-    Build: System<In = Trans<Prev, <Trig::Out as TriggerOut>::Ok>, Out = Next>,
-    Next: Component + EntityState,
+    Out: 'static,
+    Build: System<In = Trans<Prev, Out>, Out = Next>,
+    Next: NextState,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TransitionImpl")
-            .field("trigger", &self.trigger.type_id())
-            .field("builder", &self.builder.type_id())
-            .field("phantom", &self.phantom)
+            .field("builder", &self.builder.name())
+            .field("prev", &type_name::<Prev>())
+            .field("next", &type_name::<Next>())
             .finish()
     }
 }
 
-impl<Trig, Prev, Build, Next> Transition for TransitionImpl<Trig, Prev, Build, Next>
+impl<Prev, Out, Build, Next> Transition for TransitionImpl<Prev, Out, Build, Next>
 where
-    Trig: EntityTrigger,
     Prev: EntityState,
-    Build: System<In = Trans<Prev, <Trig::Out as TriggerOut>::Ok>, Out = Next>,
-    Next: Component + EntityState,
+    Out: 'static,
+    Build: System<In = Trans<Prev, Out>, Out = Next>,
+    Next: NextState,
 {
     fn init(&mut self, world: &mut World) {
-        self.trigger.init(world);
         self.builder.initialize(world);
+        init_action(&mut self.action, world);
     }
 
-    fn check<'a>(
-        &'a mut self,
-        world: &World,
+    fn target(&self) -> Target {
+        Target::State(self.target)
+    }
+
+    fn take(
+        &mut self,
+        world: &mut World,
         entity: Entity,
-    ) -> Result<Option<(Box<dyn 'a + FnOnce(&mut World, TypeId) -> Result>, TypeId)>> {
-        Ok(self
-            .trigger
-            .check(entity, world)?
-            .into_result()
-            .map(|out| {
-                (
-                    Box::new(move |world: &mut World, curr: TypeId| {
-                        let prev = Prev::remove(entity, world, curr);
-                        let next = self
-                            .builder
-                            .run(TransCtx { prev, out, entity }, world)
-                            .map_err(|err| err.to_string())?;
-                        world.entity_mut(entity).insert(next);
-                        OK
-                    }) as Box<dyn 'a + FnOnce(&mut World, TypeId) -> Result>,
-                    TypeId::of::<Next>(),
-                )
-            })
-            .ok())
+        curr: TypeId,
+        out: Box<dyn Any + Send>,
+    ) -> Result {
+        let out = *out
+            .downcast::<Out>()
+            .map_err(|_| "Transition was given the wrong trigger output")?;
+        let prev = Prev::remove(entity, world, curr);
+        let ctx = TransCtx { prev, out, entity };
+        run_action(&mut self.action, &ctx, world)?;
+        if despawned(world, entity) {
+            return OK;
+        }
+
+        let next = self
+            .builder
+            .run(ctx, world)
+            .map_err(|err| err.to_string())?;
+        next.insert_next(&mut world.entity_mut(entity));
+        OK
+    }
+
+    fn set_action(&mut self, action: Box<dyn Any>) {
+        self.action = Some(downcast_action(action));
     }
 }
 
-impl<Trig, Prev, Build, Next> TransitionImpl<Trig, Prev, Build, Next>
+impl<Prev, Out, Build, Next> TransitionImpl<Prev, Out, Build, Next>
 where
-    Trig: EntityTrigger,
     Prev: EntityState,
-    Build: System<In = Trans<Prev, <Trig::Out as TriggerOut>::Ok>, Out = Next>,
-    Next: Component + EntityState,
+    Out: 'static,
+    Build: System<In = Trans<Prev, Out>, Out = Next>,
+    Next: NextState,
 {
-    pub fn new(trigger: Trig, builder: Build) -> Self {
+    pub fn new(builder: Build, target: TypeId) -> Self {
         Self {
-            trigger,
             builder,
+            action: None,
+            target,
             phantom: PhantomData,
         }
     }
 }
 
+/// An edge in the state machine that returns to a state from the entity's [`StateHistory`]. The
+/// type parameters are the previous state and the output of the [`EntityTrigger`] that causes this
+/// transition.
+struct HistoryTransition<Prev: EntityState, Out: 'static> {
+    history: History,
+    action: Option<Action<Prev, Out>>,
+    phantom: PhantomData<Prev>,
+}
+
+impl<Prev: EntityState, Out: 'static> Debug for HistoryTransition<Prev, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HistoryTransition")
+            .field("history", &self.history)
+            .field("prev", &type_name::<Prev>())
+            .finish()
+    }
+}
+
+impl<Prev: EntityState, Out: 'static> Transition for HistoryTransition<Prev, Out> {
+    fn init(&mut self, world: &mut World) {
+        init_action(&mut self.action, world);
+    }
+
+    fn target(&self) -> Target {
+        Target::History(self.history)
+    }
+
+    fn take(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        curr: TypeId,
+        out: Box<dyn Any + Send>,
+    ) -> Result {
+        let out = *out
+            .downcast::<Out>()
+            .map_err(|_| "Transition was given the wrong trigger output")?;
+        // The machine inserts the restored state
+        let prev = Prev::remove(entity, world, curr);
+        run_action(&mut self.action, &TransCtx { prev, out, entity }, world)
+    }
+
+    fn set_action(&mut self, action: Box<dyn Any>) {
+        self.action = Some(downcast_action(action));
+    }
+}
+
+/// The state that a transition goes to
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    State(TypeId),
+    /// A state from the entity's [`StateHistory`]
+    History(History),
+}
+
 /// Context for a transition
 pub struct TransCtx<Prev, Out> {
     /// Previous state
@@ -135,19 +288,320 @@ pub struct TransCtx<Prev, Out> {
 /// Context for a transition, usable as a `SystemInput`
 pub type Trans<Prev, Out> = In<TransCtx<Prev, Out>>;
 
+/// Borrowed context for a transition, usable as a `SystemInput`. Given to transitions' actions. See
+/// [`StateMachine::with_action`].
+pub type TransRef<'a, Prev, Out> = InRef<'a, TransCtx<Prev, Out>>;
+
+/// A transition's action. See [`StateMachine::with_action`].
+type Action<Prev, Out> = BoxedSystem<TransRef<'static, Prev, Out>>;
+
+fn downcast_action<Prev: 'static, Out: 'static>(action: Box<dyn Any>) -> Action<Prev, Out> {
+    *action.downcast::<Action<Prev, Out>>().unwrap_or_else(|_| {
+        panic!(
+            "the action's input must be `TransRef<{}, {}>`, to match its transition",
+            type_name::<Prev>(),
+            type_name::<Out>(),
+        )
+    })
+}
+
+fn init_action<Prev: 'static, Out: 'static>(
+    action: &mut Option<Action<Prev, Out>>,
+    world: &mut World,
+) {
+    if let Some(action) = action {
+        action.initialize(world);
+    }
+}
+
+fn run_action<Prev: 'static, Out: 'static>(
+    action: &mut Option<Action<Prev, Out>>,
+    ctx: &TransCtx<Prev, Out>,
+    world: &mut World,
+) -> Result {
+    if let Some(action) = action {
+        action.run(ctx, world).map_err(|err| err.to_string())?;
+    }
+
+    OK
+}
+
+/// What a machine will do, found by checking its triggers. Transitions are found for every machine
+/// in parallel, and then taken one machine at a time.
+#[derive(Default)]
+struct Pending {
+    step: Step,
+    /// One for each region, in order
+    regions: Vec<Pending>,
+}
+
+impl Pending {
+    /// Whether the machine, its sub-machines, or its regions will transition
+    fn moves(&self) -> bool {
+        let moves = match &self.step {
+            Step::Stay => false,
+            Step::Sub { pending, .. } => pending.moves(),
+            Step::Enter(_) | Step::Trans { .. } | Step::Choose { .. } => true,
+        };
+
+        moves || self.regions.iter().any(Pending::moves)
+    }
+}
+
+#[derive(Default)]
+enum Step {
+    /// No transition was triggered
+    #[default]
+    Stay,
+    /// This sub-machine is in no state, so enter its initial state. Contains the parent state.
+    Enter(StateInfo),
+    /// Take the transition at `index`, using the trigger's output
+    Trans {
+        current: TypeId,
+        index: usize,
+        out: Box<dyn Any + Send>,
+    },
+    /// Multiple transitions were triggered, so choose one of them by weight
+    Choose {
+        current: TypeId,
+        triggered: Vec<(usize, Box<dyn Any + Send>)>,
+    },
+    /// No transition was triggered, so defer to the active sub-machine
+    Sub {
+        current: TypeId,
+        pending: Box<Pending>,
+    },
+}
+
+/// A state that an entity is in. See [`StateMachine::current_state`].
+#[derive(Clone, Copy, Debug)]
+pub struct CurrentState {
+    /// The state's `TypeId`
+    pub id: TypeId,
+    /// The state's type name
+    pub name: &'static str,
+    /// How long the entity has been in the state, if it has a [`StateTimer`]
+    pub elapsed: Option<Duration>,
+}
+
+/// Triggered for an entity whenever its state machine takes a transition, including transitions
+/// of sub-machines and regions, so it may be observed with `observe`. Entering or leaving a
+/// sub-machine along with its parent state doesn't count. Observers run after the machines have
+/// finished transitioning. It's also written as a message if
+/// `StateMachinePlugin::transition_messages` is enabled.
+#[derive(EntityEvent, Message, Clone, Debug)]
+pub struct Transitioned {
+    /// The entity that transitioned
+    pub entity: Entity,
+    /// The state that the entity left
+    pub prev: TypeId,
+    /// The type name of the state that the entity left
+    pub prev_name: &'static str,
+    /// The state that the entity entered
+    pub next: TypeId,
+    /// The type name of the state that the entity entered
+    pub next_name: &'static str,
+    /// The transition's index in its machine, in priority order. See `StateMachine::transitions`.
+    pub index: usize,
+}
+
+/// Triggered for an entity whenever one of its state machines reaches a final state, so it may be
+/// observed with `observe`. Observers run after the machines have finished transitioning. It's also
+/// written as a message if `StateMachinePlugin::transition_messages` is enabled. See
+/// [`StateMachine::final_state`].
+#[derive(EntityEvent, Message, Clone, Debug)]
+pub struct Completed {
+    /// The entity whose machine completed
+    pub entity: Entity,
+    /// The final state that the entity entered
+    pub state: TypeId,
+    /// The type name of the final state
+    pub state_name: &'static str,
+    /// The `Done` marker that was inserted, if any
+    pub done: Option<Done>,
+}
+
+/// Builds a new copy of a transition's trigger, for an entity that uses a [`StateMachineDef`]
+type TriggerFactory = Box<dyn Fn() -> Box<dyn TransitionTrigger> + Send + Sync>;
+
+/// A transition and the states that it may be taken from
+struct Edge {
+    source: StateMatcher,
+    transition: Box<dyn Transition>,
+    /// The index of the transition's trigger in its machine's [`Triggers`]
+    trigger: usize,
+    /// Builds the trigger for each entity that uses a [`StateMachineDef`]
+    new_trigger: TriggerFactory,
+    /// The trigger's name, for introspection and debug information
+    trigger_name: Cow<'static, str>,
+    /// See [`StateMachine::with_priority`]
+    priority: i32,
+    /// See [`StateMachine::with_weight`]
+    weight: f32,
+}
+
+/// How a [`StateMachine`] chooses between transitions that are triggered at once. See
+/// [`StateMachine::with_selection`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Selection {
+    /// Take the first triggered transition, in priority order. The transitions after it aren't
+    /// checked.
+    #[default]
+    First,
+    /// Check every transition of the highest priority that has a triggered transition, and choose
+    /// one of the triggered ones at random, by weight (see [`StateMachine::with_weight`]). Random
+    /// numbers come from the [`TransitionRng`] resource.
+    Weighted,
+    /// Check every transition of the highest priority that has a triggered transition, and take
+    /// the one whose trigger output the highest [`Score`], if it's at least `threshold`. Scores
+    /// trigger if they're positive, and are given to the transition's builder in `TransCtx::out`
+    /// (see [`StateMachine::trans_builder`]). If several have the highest score, the first is
+    /// taken. Transitions whose triggers don't output a `Score` have a score of 0, which is below
+    /// the threshold, so they're never taken.
+    Utility {
+        /// The lowest score that a transition may be taken with. Must be positive.
+        threshold: f32,
+        /// Added to the scores of transitions to the current state, so the entity doesn't switch
+        /// states whenever another score is slightly higher. If one of them has the highest score,
+        /// the entity stays in its state without transitioning.
+        hysteresis: f32,
+    },
+}
+
+impl Selection {
+    /// Returns an error if this is `Utility` with a threshold that isn't positive
+    pub(crate) fn validate(self) -> Result<Self, String> {
+        match self {
+            Self::Utility { threshold, .. } if threshold.is_nan() || threshold <= 0. => Err(
+                format!("the utility threshold must be positive, but it's {threshold}"),
+            ),
+            _ => Ok(self),
+        }
+    }
+}
+
+/// What a [`StateMachine`] does when an entity's state is inserted, removed, or replaced by
+/// something other than the machine, like a system inserting a state directly. See
+/// [`StateMachine::with_external_changes`]. Changes are noticed by observers on the machine's
+/// states, and handled the next time the machines transition, before any triggers are checked.
+/// Changes made by the machine's own events, actions, and on-update systems, including the commands
+/// they queue, and by [`TransitionCommands::transition_to`], aren't external. Neither is entering a state while in
+/// none, like when the entity is spawned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExternalChanges {
+    /// Don't look for external changes. The machine continues from whichever state the entity is
+    /// in, without running events, and reports an error if it's in no state or multiple states.
+    #[default]
+    Ignore,
+    /// Treat the change as a transition to the new state: leave the old state, running on-exit
+    /// and on-enter events, and initialize the triggers again. Reports an error if the entity
+    /// didn't move from one state to one new state.
+    Adopt,
+    /// Undo the change, putting the entity back in the states that it was in
+    Revert,
+    /// Report an error, and leave the entity as it is
+    Error,
+}
+
+/// The random number generator that weighted transitions are chosen with. See
+/// [`Selection::Weighted`]. Replace it with [`TransitionRng::with_seed`] for reproducible choices,
+/// like in tests. Transitions are chosen in a deterministic order, so a fixed seed always makes
+/// the same choices.
+#[derive(Resource, Clone, Debug)]
+pub struct TransitionRng(fastrand::Rng);
+
+impl Default for TransitionRng {
+    fn default() -> Self {
+        Self(fastrand::Rng::new())
+    }
+}
+
+impl TransitionRng {
+    /// Creates a generator with the given seed
+    pub fn with_seed(seed: u64) -> Self {
+        Self(fastrand::Rng::with_seed(seed))
+    }
+}
+
 /// Information about a state
 #[derive(Debug)]
 struct StateMetadata {
     /// For debug information
-    name: String,
+    name: &'static str,
+    /// Whether this is a component, rather than a matcher like `AnyState`
+    concrete: bool,
+    /// Clones the state out of an entity, for its [`StateHistory`]
+    snapshot: fn(EntityRef) -> Option<Box<dyn StateValue>>,
+    /// Starts recording changes to the state that weren't made by state machines
+    observe: fn(&mut World),
+    /// Starts updating `StateMachineInfo` when an entity enters or leaves the state
+    #[cfg(feature = "reflect")]
+    watch: fn(&mut World),
 }
 
 impl StateMetadata {
     fn new<S: EntityState>() -> Self {
         Self {
-            name: type_name::<S>().to_string(),
+            name: type_name::<S>(),
+            concrete: S::CONCRETE,
+            snapshot: S::snapshot,
+            observe: S::observe,
+            #[cfg(feature = "reflect")]
+            watch: S::watch,
+        }
+    }
+}
+
+fn event_info((from, to, event): &(StateMatcher, StateMatcher, OnEvent)) -> EventInfo {
+    EventInfo {
+        from: *from,
+        to: *to,
+        kind: match event {
+            OnEvent::Entity(_) => EventKind::Entity,
+            OnEvent::Command(_) => EventKind::Command,
+            OnEvent::System(_) => EventKind::System,
+        },
+    }
+}
+
+/// Runs the events that match the given change, in order
+fn run_events(
+    events: &mut [(StateMatcher, StateMatcher, OnEvent)],
+    change: StateChange,
+    world: &mut World,
+) -> Result {
+    let mut errs = ErrList::default();
+
+    for (matches_prev, matches_next, event) in events {
+        // An earlier event may have despawned the entity
+        if despawned(world, change.entity) {
+            break;
+        }
+
+        if matches_prev.matches(change.prev.id) && matches_next.matches(change.next.id) {
+            errs.push(event.trigger(change, world));
         }
     }
+
+    errs.into()
+}
+
+/// Whether the entity was despawned, such as by an event. A transition stops once its entity is
+/// despawned.
+fn despawned(world: &World, entity: Entity) -> bool {
+    world.get_entity(entity).is_err()
+}
+
+/// Finds the entry in the history that a history transition would restore, only counting the
+/// given machine's states
+fn find_history<'a>(
+    states: &TypeIdMap<StateMetadata>,
+    entity_history: Option<&'a StateHistory>,
+    history: History,
+) -> Option<&'a HistoryEntry> {
+    entity_history?.find(history.back(), |state| states.contains_key(&state))
 }
 
 /// State machine component.
@@ -156,19 +610,51 @@ impl StateMetadata {
 /// and removed based on the transitions that you add. Build one with `StateMachine::default`,
 /// `StateMachine::trans`, and other methods.
 #[derive(Component)]
+#[component(on_add = start_machine)]
+#[cfg_attr(feature = "reflect", require(crate::reflect::StateMachineInfo))]
 pub struct StateMachine {
     states: TypeIdMap<StateMetadata>,
     /// Each transition and the state it should apply in (or [`AnyState`]). We store the transitions
     /// in a flat list so that we ensure we always check them in the right order; storing them in
     /// each StateMetadata would mean that e.g. we'd have to check every AnyState trigger before any
     /// state-specific trigger or vice versa.
-    transitions: Vec<(fn(TypeId) -> bool, Box<dyn Transition>)>,
-    on_exit: Vec<(fn(TypeId) -> bool, fn(TypeId) -> bool, OnEvent)>,
-    on_enter: Vec<(fn(TypeId) -> bool, fn(TypeId) -> bool, OnEvent)>,
-    /// Transitions must be initialized whenever a transition is added or a transition occurs
-    init_transitions: bool,
+    /// The transitions are sorted by priority.
+    transitions: Vec<Edge>,
+    /// The index of the last added transition, for `with_priority` and `with_weight`
+    last_transition: Option<usize>,
+    /// How to choose between transitions that are triggered at once
+    selection: Selection,
+    on_exit: Vec<(StateMatcher, StateMatcher, OnEvent)>,
+    on_enter: Vec<(StateMatcher, StateMatcher, OnEvent)>,
+    on_update: Vec<(StateMatcher, Box<dyn UpdateSystem>)>,
+    /// Final states, with the `Done` marker to insert upon reaching each. See
+    /// [`StateMachine::final_state`].
+    final_states: TypeIdMap<Option<Done>>,
+    /// Machines nested inside states of this machine, keyed by the state they're nested in
+    sub_machines: TypeIdMap<StateMachine>,
+    /// Machines that run in parallel with this one, each with their own states
+    regions: Vec<StateMachine>,
+    /// The state that this machine enters when it's added to an entity, or entered as a sub-machine
+    initial: Option<Box<dyn StateValue>>,
+    /// The triggers of this machine, its sub-machines, and its regions. Only the outermost machine
+    /// holds them, and they're pulled out while it runs. A `StateMachineDef` holds each entity's
+    /// triggers instead.
+    triggers: Triggers,
+    /// The entities whose triggers of this machine must be initialized again, since the machine
+    /// transitioned
+    reinit: EntityHashSet,
+    /// Transitions and systems must be initialized whenever one is added
+    init_systems: bool,
     /// If true, all transitions are logged at info level
     log_transitions: bool,
+    /// See [`StateMachine::run_to_completion`]
+    max_iterations: Option<u32>,
+    /// See [`StateMachine::with_external_changes`]
+    external_changes: ExternalChanges,
+    /// See [`StateMachine::with_error_policy`]
+    error_policy: Option<ErrorPolicy>,
+    /// The name of the definition that this machine was built from, if any
+    name: Option<Cow<'static, str>>,
 }
 
 impl Default for StateMachine {
@@ -176,10 +662,23 @@ impl Default for StateMachine {
         Self {
             states: default(),
             transitions: Vec::new(),
+            last_transition: None,
+            selection: default(),
             on_exit: Vec::new(),
             on_enter: Vec::new(),
-            init_transitions: true,
+            on_update: Vec::new(),
+            final_states: default(),
+            sub_machines: default(),
+            regions: Vec::new(),
+            initial: None,
+            triggers: default(),
+            reinit: default(),
+            init_systems: true,
             log_transitions: false,
+            max_iterations: None,
+            external_changes: default(),
+            error_policy: None,
+            name: None,
         }
     }
 }
@@ -191,13 +690,24 @@ impl StateMachine {
         self
     }
 
+    /// Marks `S` as a final state. Once the entity reaches it, this machine stops checking its
+    /// transitions, inserts `done` into the entity if it's given, and triggers [`Completed`]. The
+    /// machine runs again when the entity leaves the state, like when a parent machine leaves the
+    /// state that this sub-machine is nested in, reacting to the `done` trigger.
+    pub fn final_state<S: Clone + Component>(mut self, done: Option<Done>) -> Self {
+        self.metadata_mut::<S>();
+        self.final_states.insert(TypeId::of::<S>(), done);
+        self
+    }
+
     /// Adds a transition to the state machine. When the entity is in the state given as a
     /// type parameter, and the given trigger occurs, it will transition to the state given as a
     /// function parameter. Elide the `Marker` type parameter with `_`. Transitions have priority
-    /// in the order they are added.
+    /// in the order they are added, unless given a priority with [`StateMachine::with_priority`].
+    /// Triggers must be `Clone`, so each entity that uses a [`StateMachineDef`] gets its own copy.
     pub fn trans<S: EntityState, Marker>(
         self,
-        trigger: impl IntoTrigger<Marker>,
+        trigger: impl IntoTrigger<Marker, Trigger: Clone>,
         state: impl Clone + Component,
     ) -> Self {
         self.trans_builder(trigger, move |_: Trans<S, _>| state.clone())
@@ -215,7 +725,7 @@ impl StateMachine {
     /// `Some(Next)`, the machine will transition to that `Next` state.
     pub fn trans_builder<
         Prev: EntityState,
-        Trig: IntoTrigger<TrigMarker>,
+        Trig: IntoTrigger<TrigMarker, Trigger: Clone>,
         Next: Clone + Component,
         TrigMarker,
         BuildMarker,
@@ -228,28 +738,182 @@ impl StateMachine {
             BuildMarker,
         >,
     ) -> Self {
-        self.metadata_mut::<Prev>();
         self.metadata_mut::<Next>();
-        let transition = TransitionImpl::<_, Prev, _, _>::new(
-            trigger.into_trigger(),
-            IntoSystem::into_system(builder),
+        self.add_builder(trigger.into_trigger(), builder, TypeId::of::<Next>())
+    }
+
+    /// Adds a transition to a type-erased state value, for machines built from data. See
+    /// [`StateMachine::trans`].
+    #[cfg(feature = "serde")]
+    pub(crate) fn trans_value<Prev: EntityState>(
+        self,
+        trigger: crate::registry::DataTrigger,
+        next: Box<dyn StateValue>,
+    ) -> Self {
+        let target = next.state_id();
+        next.register(self).add_builder(
+            trigger,
+            move |_: Trans<Prev, ()>| next.clone_value(),
+            target,
+        )
+    }
+
+    /// Adds a transition whose builder returns `Next`, which enters the `target` state. See
+    /// [`StateMachine::trans_builder`].
+    fn add_builder<Prev: EntityState, Trig: EntityTrigger + Clone, Next: NextState, BuildMarker>(
+        mut self,
+        trigger: Trig,
+        builder: impl IntoSystem<Trans<Prev, <Trig::Out as TriggerOut>::Ok>, Next, BuildMarker>,
+        target: TypeId,
+    ) -> Self {
+        self.metadata_mut::<Prev>();
+        let transition =
+            TransitionImpl::<Prev, _, _, _>::new(IntoSystem::into_system(builder), target);
+        self.add_transition(StateMatcher::of::<Prev>(), trigger, Box::new(transition));
+        self
+    }
+
+    /// Adds a transition, after the transitions of the same or higher priority
+    fn add_transition(
+        &mut self,
+        source: StateMatcher,
+        trigger: impl EntityTrigger + Clone,
+        transition: Box<dyn Transition>,
+    ) {
+        let index = self.transitions.partition_point(|edge| edge.priority >= 0);
+        self.transitions.insert(
+            index,
+            Edge {
+                source,
+                transition,
+                trigger: self.triggers.triggers.len(),
+                new_trigger: {
+                    let trigger = trigger.clone();
+                    Box::new(move || Box::new(trigger.clone()))
+                },
+                trigger_name: trigger.name(),
+                priority: 0,
+                weight: 1.,
+            },
         );
-        self.transitions
-            .push((Prev::matches, Box::new(transition) as Box<dyn Transition>));
-        self.init_transitions = true;
+        self.triggers.triggers.push(Box::new(trigger));
+        self.triggers.init = true;
+        self.last_transition = Some(index);
+        self.init_systems = true;
+    }
+
+    /// Sets the priority of the last added transition. Transitions with higher priorities are
+    /// checked first, and transitions with the same priority are checked in the order they were
+    /// added. Defaults to 0.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        let index = self
+            .last_transition
+            .expect("`with_priority` must be called after adding a transition");
+        let mut edge = self.transitions.remove(index);
+        edge.priority = priority;
+
+        let index = self
+            .transitions
+            .partition_point(|edge| edge.priority >= priority);
+        self.transitions.insert(index, edge);
+        self.last_transition = Some(index);
+        self
+    }
+
+    /// Sets the weight of the last added transition, for [`Selection::Weighted`]. A transition's
+    /// chance of being chosen is its weight divided by the total weight of the triggered
+    /// transitions. Transitions with a weight of 0 are never chosen. Defaults to 1.
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        let index = self
+            .last_transition
+            .expect("`with_weight` must be called after adding a transition");
+        self.transitions[index].weight = weight;
+        self
+    }
+
+    /// Gives the last added transition an action, a system that runs whenever the transition is
+    /// taken. It runs after the on-exit events and before the next state is built, so it may read
+    /// the previous state and the trigger's output in its `TransRef`, whose types must match the
+    /// transition's. Giving the transition another action replaces the first.
+    pub fn with_action<Prev: 'static, Out: 'static, Marker>(
+        mut self,
+        action: impl IntoSystem<TransRef<'static, Prev, Out>, (), Marker>,
+    ) -> Self {
+        let index = self
+            .last_transition
+            .expect("`with_action` must be called after adding a transition");
+        let action: Action<Prev, Out> = Box::new(IntoSystem::into_system(action));
+        self.transitions[index]
+            .transition
+            .set_action(Box::new(action));
+        self.init_systems = true;
+        self
+    }
+
+    /// Sets how this machine chooses between transitions that are triggered at once. Defaults to
+    /// [`Selection::First`]. Doesn't affect sub-machines or regions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it's [`Selection::Utility`] with a threshold that isn't positive.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection.validate().unwrap_or_else(|err| panic!("{err}"));
+        self
+    }
+
+    /// Sets what this machine does when an entity's state is changed by something other than the
+    /// machine. Defaults to [`ExternalChanges::Ignore`]. Doesn't affect sub-machines or regions.
+    pub fn with_external_changes(mut self, external_changes: ExternalChanges) -> Self {
+        self.external_changes = external_changes;
+        self.init_systems = true;
+        self
+    }
+
+    /// Sets what happens to an entity when this machine fails, overriding the [`ErrorPolicy`]
+    /// resource. Applies to errors from this machine's sub-machines and regions too.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = Some(error_policy);
+        self
+    }
+
+    /// Adds a history transition to the state machine. When the entity is in `Prev` state, and the
+    /// given trigger occurs, it will return to a state that it was in before, chosen by `history`.
+    /// This requires the entity to have a [`StateHistory`]. If there isn't such a state in the
+    /// history, the transition isn't taken. Elide the `Marker` type parameter with `_`.
+    pub fn trans_history<Prev: EntityState, Marker>(
+        self,
+        trigger: impl IntoTrigger<Marker, Trigger: Clone>,
+        history: History,
+    ) -> Self {
+        self.add_history::<Prev, _>(trigger.into_trigger(), history)
+    }
+
+    /// Adds a history transition. See [`StateMachine::trans_history`].
+    fn add_history<Prev: EntityState, Trig: EntityTrigger + Clone>(
+        mut self,
+        trigger: Trig,
+        history: History,
+    ) -> Self {
+        self.metadata_mut::<Prev>();
+        let transition = HistoryTransition::<Prev, <Trig::Out as TriggerOut>::Ok> {
+            history,
+            action: None,
+            phantom: PhantomData,
+        };
+        self.add_transition(StateMatcher::of::<Prev>(), trigger, Box::new(transition));
         self
     }
 
     /// Adds an on-enter event to the state machine. Whenever the state machine transitions
     /// from the given previous state to the given next state, it will run the event. This will not
-    /// occur on manual transitions.
+    /// occur on manual transitions, unless they use `transition_to`.
     pub fn on_enter_to<Prev: EntityState, Next: EntityState>(
         mut self,
         on_enter: impl 'static + Fn(&mut EntityCommands) + Send + Sync,
     ) -> Self {
         self.on_enter.push((
-            Prev::matches,
-            Next::matches,
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
             OnEvent::Entity(Box::new(on_enter)),
         ));
 
@@ -258,7 +922,7 @@ impl StateMachine {
 
     /// Adds an on-enter event to the state machine. Whenever the state machine transitions
     /// from any previous state to the given next state, it will run the event. This will not occur
-    /// on manual transitions.
+    /// on manual transitions, unless they use `transition_to`.
     pub fn on_enter<Next: EntityState>(
         self,
         on_enter: impl 'static + Fn(&mut EntityCommands) + Send + Sync,
@@ -268,14 +932,14 @@ impl StateMachine {
 
     /// Adds an on-enter event to the state machine. Whenever the state machine transitions
     /// from the given previous state to the given next state, it will run the event. This will not
-    /// occur on manual transitions.
+    /// occur on manual transitions, unless they use `transition_to`.
     pub fn on_exit_from<Prev: EntityState, Next: EntityState>(
         mut self,
         on_exit: impl 'static + Fn(&mut EntityCommands) + Send + Sync,
     ) -> Self {
         self.on_exit.push((
-            Prev::matches,
-            Next::matches,
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
             OnEvent::Entity(Box::new(on_exit)),
         ));
 
@@ -284,7 +948,7 @@ impl StateMachine {
 
     /// Adds an on-exit event to the state machine. Whenever the state machine transitions
     /// from the given previous state to any next state, it will run the event. This will not occur
-    /// on manual transitions.
+    /// on manual transitions, unless they use `transition_to`.
     pub fn on_exit<Prev: EntityState>(
         self,
         on_exit: impl 'static + Fn(&mut EntityCommands) + Send + Sync,
@@ -294,14 +958,14 @@ impl StateMachine {
 
     /// Adds an on-enter command to the state machine. Whenever the state machine transitions
     /// from the given previous state to the given next state, it will run the command. This will
-    /// not occur on manual transitions.
+    /// not occur on manual transitions, unless they use `transition_to`.
     pub fn command_on_enter_to<Prev: EntityState, Next: EntityState>(
         mut self,
         command: impl Clone + Command + Sync,
     ) -> Self {
         self.on_enter.push((
-            Prev::matches,
-            Next::matches,
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
             OnEvent::Command(Box::new(command)),
         ));
 
@@ -310,21 +974,21 @@ impl StateMachine {
 
     /// Adds an on-enter command to the state machine. Whenever the state machine transitions
     /// from any previous state to the given next state, it will run the command. This will not
-    /// occur on manual transitions.
+    /// occur on manual transitions, unless they use `transition_to`.
     pub fn command_on_enter<Next: EntityState>(self, command: impl Clone + Command + Sync) -> Self {
         self.command_on_enter_to::<AnyState, Next>(command)
     }
 
     /// Adds an on-exit command to the state machine. Whenever the state machine transitions
     /// from the given previous state to the given next state, it will run the command. This will
-    /// not occur on manual transitions.
+    /// not occur on manual transitions, unless they use `transition_to`.
     pub fn command_on_exit_from<Prev: EntityState, Next: EntityState>(
         mut self,
         command: impl Clone + Command + Sync,
     ) -> Self {
         self.on_exit.push((
-            Prev::matches,
-            Next::matches,
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
             OnEvent::Command(Box::new(command)),
         ));
 
@@ -333,225 +997,3509 @@ impl StateMachine {
 
     /// Adds an on-exit command to the state machine. Whenever the state machine transitions
     /// from the given previous state to any next state, it will run the command. This will not
-    /// occur on manual transitions.
+    /// occur on manual transitions, unless they use `transition_to`.
     pub fn command_on_exit<Prev: EntityState>(self, command: impl Clone + Command + Sync) -> Self {
         self.command_on_exit_from::<Prev, AnyState>(command)
     }
 
-    /// Sets whether transitions are logged to the console
-    pub fn set_trans_logging(mut self, log_transitions: bool) -> Self {
-        self.log_transitions = log_transitions;
+    /// Adds an on-enter system to the state machine. Whenever the state machine transitions
+    /// from the given previous state to the given next state, it will run the system. The system
+    /// may take `In<Entity>`, `In<StateChange>`, or no input. It runs immediately, with access to
+    /// the world, and is initialized once, so its `Local`s are kept between runs. This will not
+    /// occur on manual transitions, unless they use `transition_to`.
+    pub fn system_on_enter_to<Prev: EntityState, Next: EntityState, I: EventIn, Marker>(
+        mut self,
+        system: impl IntoSystem<I, (), Marker>,
+    ) -> Self {
+        self.on_enter.push((
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
+            OnEvent::System(Box::new(IntoSystem::into_system(system))),
+        ));
+
         self
     }
 
-    /// Initialize all transitions. Must be executed before `run`. This is separate because `run` is
-    /// parallelizable (takes a `&World`) but this isn't (takes a `&mut World`).
-    fn init_transitions(&mut self, world: &mut World) {
-        if !self.init_transitions {
-            return;
-        }
-
-        for (_, transition) in &mut self.transitions {
-            transition.init(world);
-        }
-
-        self.init_transitions = false;
+    /// Adds an on-enter system to the state machine. Whenever the state machine transitions
+    /// from any previous state to the given next state, it will run the system. See
+    /// [`StateMachine::system_on_enter_to`].
+    pub fn system_on_enter<Next: EntityState, I: EventIn, Marker>(
+        self,
+        system: impl IntoSystem<I, (), Marker>,
+    ) -> Self {
+        self.system_on_enter_to::<AnyState, Next, I, Marker>(system)
     }
 
-    /// Runs all transitions until one is actually taken. If one is taken, logs the transition and
-    /// runs `on_enter/on_exit` triggers.
-    // TODO Defer the actual transition so this can be parallelized, and see if that improves perf
-    fn run(&mut self, world: &mut World, entity: Entity) -> Result {
-        let mut states = self.states.keys();
-        let current = states.find(|&&state| world.entity(entity).contains_type_id(state));
+    /// Adds an on-exit system to the state machine. Whenever the state machine transitions
+    /// from the given previous state to the given next state, it will run the system. The system
+    /// may take `In<Entity>`, `In<StateChange>`, or no input. It runs immediately, with access to
+    /// the world, and is initialized once, so its `Local`s are kept between runs. This will not
+    /// occur on manual transitions, unless they use `transition_to`.
+    pub fn system_on_exit_from<Prev: EntityState, Next: EntityState, I: EventIn, Marker>(
+        mut self,
+        system: impl IntoSystem<I, (), Marker>,
+    ) -> Self {
+        self.on_exit.push((
+            StateMatcher::of::<Prev>(),
+            StateMatcher::of::<Next>(),
+            OnEvent::System(Box::new(IntoSystem::into_system(system))),
+        ));
 
-        let Some(&current) = current else {
-            return Err(format!("Entity {entity:?} is in no state").into());
-        };
+        self
+    }
 
-        let from = &self.states[&current];
-        if let Some(&other) = states.find(|&&state| world.entity(entity).contains_type_id(state)) {
-            let state = &from.name;
-            let other = &self.states[&other].name;
-            return Err(format!("{entity:?} is in multiple states: {state} and {other}").into());
-        }
+    /// Adds an on-exit system to the state machine. Whenever the state machine transitions
+    /// from the given previous state to any next state, it will run the system. See
+    /// [`StateMachine::system_on_exit_from`].
+    pub fn system_on_exit<Prev: EntityState, I: EventIn, Marker>(
+        self,
+        system: impl IntoSystem<I, (), Marker>,
+    ) -> Self {
+        self.system_on_exit_from::<Prev, AnyState, I, Marker>(system)
+    }
 
-        let Some((trans, next_state)) = self
-            .transitions
-            .iter_mut()
-            .filter(|(matches, _)| matches(current))
-            .find_map(|(_, transition)| transition.check(world, entity).transpose())
-            .transpose()?
-        else {
-            return OK;
-        };
-        let to = &self.states[&next_state];
+    /// Adds an on-update system to the state machine. Every time the machines update, before any
+    /// transitions, it runs for each entity in the given state, including entities whose
+    /// sub-machines or regions are in it. The system may take `In<Entity>` or no input. It's
+    /// initialized once, so its `Local`s are kept when the machine transitions. See
+    /// `StateSet::OnUpdate`.
+    pub fn on_update<S: EntityState, I: TriggerIn, Marker>(
+        mut self,
+        system: impl IntoSystem<I, (), Marker>,
+    ) -> Self {
+        self.on_update.push((
+            StateMatcher::of::<S>(),
+            Box::new(IntoSystem::into_system(system)),
+        ));
 
-        for (matches_current, matches_next, event) in &self.on_exit {
-            if matches_current(current) && matches_next(next_state) {
-                event.trigger(entity, &mut world.commands());
-            }
-        }
+        self
+    }
 
-        trans(world, current)?;
+    /// Nests a sub-machine inside the given state. Entering `S` also enters `initial`, and leaving
+    /// `S` removes whichever of the sub-machine's states is active. This machine's transitions take
+    /// priority over the sub-machine's, so the sub-machine only runs on frames where this machine
+    /// doesn't transition. The sub-machine must not share states with this machine.
+    pub fn sub_machine<S: Clone + Component>(
+        mut self,
+        initial: impl Clone + Component,
+        mut machine: StateMachine,
+    ) -> Self {
+        let triggers = std::mem::take(&mut machine.triggers);
+        self.triggers
+            .sub_machines
+            .insert(TypeId::of::<S>(), triggers);
+        self.metadata_mut::<S>();
+        machine.register_initial(initial);
+        self.sub_machines.insert(TypeId::of::<S>(), machine);
+        self
+    }
 
-        for (matches_current, matches_next, event) in &self.on_enter {
-            if matches_current(current) && matches_next(next_state) {
-                event.trigger(entity, &mut world.commands());
-            }
-        }
+    /// Adds a region, a machine that runs in parallel with this one on the same entity. Each region
+    /// has its own states, transitions, and events, and is in exactly one of its states, so an
+    /// entity may be in a movement state and a weapon state at once. Regions are run in the order
+    /// they are added, after this machine's own transitions, in the same pass. Regions must not
+    /// share states with each other or with this machine. A machine that only holds regions doesn't
+    /// need any states of its own.
+    pub fn region(mut self, mut machine: StateMachine) -> Self {
+        let triggers = std::mem::take(&mut machine.triggers);
+        self.triggers.regions.push(triggers);
+        self.regions.push(machine);
+        self
+    }
 
-        if self.log_transitions {
-            info!("{entity:?} transitioned from {} to {}", from.name, to.name);
-        }
+    /// Sets the state that the entity enters when this machine is added to it, so it doesn't need
+    /// to be spawned in a state. If the entity is already in one of the machine's states, it stays
+    /// in it. Otherwise, the state is inserted, and its on-enter events run, with the state itself
+    /// as the previous state. Regions may have initial states too. Sub-machines' initial states are
+    /// given to [`StateMachine::sub_machine`].
+    pub fn initial(mut self, initial: impl Clone + Component) -> Self {
+        self.register_initial(initial);
+        self
+    }
 
-        self.init_transitions = true;
+    fn register_initial<S: Clone + Component>(&mut self, initial: S) {
+        self.metadata_mut::<S>();
+        self.initial = Some(Box::new(initial));
+    }
 
-        OK
+    /// [`StateMachine::initial`] for a state value built from data
+    #[cfg(feature = "serde")]
+    pub(crate) fn with_initial_value(mut self, initial: Box<dyn StateValue>) -> Self {
+        self = initial.register(self);
+        self.initial = Some(initial);
+        self
     }
-}
 
-/// Runs all transitions on all entities.
-// There are comments here about parallelization, but this is not parallelized anymore. Leaving them
-// here in case it gets parallelized again.
-pub(crate) fn transition(
-    world: &mut World,
-    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
-) -> Result {
-    // Pull the machines out of the world so we can invoke mutable methods on them. The alternative
-    // would be to wrap the entire `StateMachine` in an `Arc<Mutex>`, but that would complicate the
-    // API surface and you wouldn't be able to do anything more anyway (since you'd need to lock the
-    // mutex anyway).
-    let mut borrowed_machines: Vec<(Entity, StateMachine)> = machine_query
-        .iter_mut(world)
-        .map(|(entity, mut machine)| {
-            let stub = StateMachine::default();
-            (entity, std::mem::replace(machine.as_mut(), stub))
-        })
-        .collect();
+    /// Sets whether transitions are logged to the console
+    pub fn set_trans_logging(mut self, log_transitions: bool) -> Self {
+        self.log_transitions = log_transitions;
+        self
+    }
 
-    // `world` is mutable here, since initialization requires mutating the world
-    for (_, machine) in borrowed_machines.iter_mut() {
-        machine.init_transitions(world);
+    /// Makes the machine keep transitioning in the same run until none of its transitions are
+    /// triggered, instead of taking at most one transition per run. This way, a chain of
+    /// transitions whose triggers are already satisfied is taken in a single frame. The
+    /// transitions are checked at most `max_iterations` times per run. If the machine still
+    /// transitioned in the last of them, it reports an error, since its transitions probably form
+    /// a loop, and continues on the next run. Only applies to the outermost machine, and includes
+    /// its sub-machines and regions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_iterations` is 0.
+    pub fn run_to_completion(mut self, max_iterations: u32) -> Self {
+        assert!(max_iterations > 0, "`max_iterations` must be at least 1");
+        self.max_iterations = Some(max_iterations);
+        self
     }
 
-    // `world` is not mutated here; the state machines are not in the world, and the Commands don't
-    // mutate until application
-    // let par_commands = system_state.get(world);
-    // let task_pool = ComputeTaskPool::get();
+    /// Names this machine after the definition that it was built from, so that it can be found
+    /// again when loading. `MachineRegistry::build` names the machines that it builds.
+    pub fn with_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The name of the definition that this machine was built from. See
+    /// [`StateMachine::with_name`].
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The states registered in this machine, not counting its sub-machines' or regions', sorted by
+    /// name. Matchers like `AnyState` aren't included.
+    pub fn states(&self) -> Vec<StateInfo> {
+        let mut states = self
+            .states
+            .iter()
+            .filter(|(_, metadata)| metadata.concrete)
+            .map(|(&id, metadata)| StateInfo {
+                id,
+                name: metadata.name,
+            })
+            .collect::<Vec<_>>();
+        states.sort_by_key(|state| state.name);
+        states
+    }
+
+    /// This machine's transitions, in priority order
+    pub fn transitions(&self) -> impl Iterator<Item = TransitionInfo> + '_ {
+        self.transitions.iter().map(|edge| TransitionInfo {
+            source: edge.source,
+            target: match edge.transition.target() {
+                Target::State(id) => TransitionTarget::State(StateInfo {
+                    id,
+                    name: self.states[&id].name,
+                }),
+                Target::History(history) => TransitionTarget::History(history),
+            },
+            trigger: edge.trigger_name.clone(),
+            priority: edge.priority,
+            weight: edge.weight,
+        })
+    }
+
+    /// This machine's on-enter events, in the order they run
+    pub fn on_enter_events(&self) -> impl Iterator<Item = EventInfo> + '_ {
+        self.on_enter.iter().map(event_info)
+    }
+
+    /// This machine's on-exit events, in the order they run
+    pub fn on_exit_events(&self) -> impl Iterator<Item = EventInfo> + '_ {
+        self.on_exit.iter().map(event_info)
+    }
+
+    /// The sub-machine nested in the given state, if any. See [`StateMachine::sub_machine`].
+    pub fn get_sub_machine(&self, state: TypeId) -> Option<&StateMachine> {
+        self.sub_machines.get(&state)
+    }
+
+    /// The states that have sub-machines, with their sub-machines, sorted by the states' names
+    pub fn sub_machines(&self) -> Vec<(StateInfo, &StateMachine)> {
+        let mut sub_machines = self
+            .sub_machines
+            .iter()
+            .map(|(&id, machine)| {
+                let name = self.states[&id].name;
+                (StateInfo { id, name }, machine)
+            })
+            .collect::<Vec<_>>();
+        sub_machines.sort_by_key(|(state, _)| state.name);
+        sub_machines
+    }
+
+    /// This machine's regions, in order. See [`StateMachine::region`].
+    pub fn regions(&self) -> &[StateMachine] {
+        &self.regions
+    }
+
+    /// The state that this machine enters when it's added to an entity, or entered as a
+    /// sub-machine. See [`StateMachine::initial`].
+    pub fn initial_state(&self) -> Option<StateInfo> {
+        let id = self.initial.as_ref()?.state_id();
+        Some(StateInfo {
+            id,
+            name: self.states[&id].name,
+        })
+    }
+
+    /// Initialize all transitions and on-enter, on-exit, and on-update systems. Must be executed
+    /// before `check`. This is separate because `check` is parallelizable (takes a `&World`) but
+    /// this isn't (takes a `&mut World`).
+    fn init_systems(&mut self, world: &mut World) {
+        if !self.init_systems {
+            return;
+        }
+
+        for edge in &mut self.transitions {
+            edge.transition.init(world);
+        }
+
+        for (_, _, event) in self.on_enter.iter_mut().chain(&mut self.on_exit) {
+            event.init(world);
+        }
+
+        for (_, system) in &mut self.on_update {
+            system.init(world);
+        }
+
+        if self.external_changes != ExternalChanges::Ignore {
+            for metadata in self.states.values() {
+                (metadata.observe)(world);
+            }
+        }
+
+        self.init_systems = false;
+    }
+
+    /// Initializes the transitions and systems of this machine, its sub-machines, and its regions
+    pub(crate) fn init_all_systems(&mut self, world: &mut World) {
+        self.init_systems(world);
+
+        for sub_machine in self.sub_machines.values_mut() {
+            sub_machine.init_all_systems(world);
+        }
+
+        for region in &mut self.regions {
+            region.init_all_systems(world);
+        }
+    }
+
+    /// Initializes the entity's triggers of this machine, its sub-machines, and its regions, if
+    /// they're new or their machine transitioned since they were last initialized. Must be executed
+    /// before `check`.
+    fn init_triggers(&self, world: &mut World, entity: Entity, triggers: &mut Triggers) {
+        if triggers.init || self.reinit.contains(&entity) {
+            for trigger in &mut triggers.triggers {
+                trigger.init(world);
+            }
+
+            triggers.init = false;
+        }
+
+        for (state, sub_machine) in &self.sub_machines {
+            if let Some(triggers) = triggers.sub_machines.get_mut(state) {
+                sub_machine.init_triggers(world, entity, triggers);
+            }
+        }
+
+        for (region, triggers) in self.regions.iter().zip(&mut triggers.regions) {
+            region.init_triggers(world, entity, triggers);
+        }
+    }
+
+    /// Initializes the entity's triggers of this machine, its sub-machines, and its regions whose
+    /// machine transitioned, and forgets that they must be initialized again. Runs right after the
+    /// entity changes states, so triggers like `time_in_state` count from when it entered them,
+    /// rather than from the machine's next run.
+    fn reinit_triggers(&mut self, world: &mut World, entity: Entity, triggers: &mut Triggers) {
+        if self.reinit.remove(&entity) {
+            for trigger in &mut triggers.triggers {
+                trigger.init(world);
+            }
+
+            triggers.init = false;
+        }
+
+        for (state, sub_machine) in &mut self.sub_machines {
+            if let Some(triggers) = triggers.sub_machines.get_mut(state) {
+                sub_machine.reinit_triggers(world, entity, triggers);
+            }
+        }
+
+        for (region, triggers) in self.regions.iter_mut().zip(&mut triggers.regions) {
+            region.reinit_triggers(world, entity, triggers);
+        }
+    }
+
+    /// Builds a new copy of the triggers of this machine, its sub-machines, and its regions, for an
+    /// entity that uses a [`StateMachineDef`]
+    fn new_triggers(&self) -> Triggers {
+        let mut edges = self.transitions.iter().collect::<Vec<_>>();
+        edges.sort_by_key(|edge| edge.trigger);
+
+        Triggers {
+            triggers: edges.iter().map(|edge| (edge.new_trigger)()).collect(),
+            init: true,
+            sub_machines: self
+                .sub_machines
+                .iter()
+                .map(|(&state, sub_machine)| (state, sub_machine.new_triggers()))
+                .collect(),
+            regions: self
+                .regions
+                .iter()
+                .map(StateMachine::new_triggers)
+                .collect(),
+        }
+    }
+
+    /// Forgets that the given entities' triggers must be initialized again, once they were
+    fn clear_reinit(&mut self, initialized: &impl Fn(Entity) -> bool) {
+        self.reinit.retain(|&entity| !initialized(entity));
+
+        for machine in self.sub_machines.values_mut().chain(&mut self.regions) {
+            machine.clear_reinit(initialized);
+        }
+    }
+
+    /// Saves the data of every trigger in this machine, its sub-machines, and its regions, in a
+    /// stable order
+    #[cfg(feature = "serde")]
+    pub(crate) fn save_triggers(&self, world: &World) -> Vec<Option<serde_json::Value>> {
+        let mut data = Vec::new();
+        self.save_triggers_of(&self.triggers, world, &mut data);
+        data
+    }
+
+    /// Saves the data of the given triggers of this machine, then of its sub-machines sorted by
+    /// state name, then of its regions
+    #[cfg(feature = "serde")]
+    fn save_triggers_of(
+        &self,
+        triggers: &Triggers,
+        world: &World,
+        data: &mut Vec<Option<serde_json::Value>>,
+    ) {
+        data.extend(
+            self.transitions
+                .iter()
+                .map(|edge| triggers.triggers[edge.trigger].save(world)),
+        );
+
+        for (state, sub_machine) in self.sub_machines() {
+            if let Some(triggers) = triggers.sub_machines.get(&state.id) {
+                sub_machine.save_triggers_of(triggers, world, data);
+            }
+        }
+
+        for (region, triggers) in self.regions.iter().zip(&triggers.regions) {
+            region.save_triggers_of(triggers, world, data);
+        }
+    }
+
+    /// Initializes the machine on the given entity, and loads trigger data from
+    /// [`StateMachine::save_triggers`] into it. The triggers are initialized first, since
+    /// initializing them later would reset the data.
+    #[cfg(feature = "serde")]
+    pub(crate) fn load_triggers(
+        &mut self,
+        data: Vec<Option<serde_json::Value>>,
+        world: &mut World,
+        entity: Entity,
+    ) -> Result {
+        self.init_all_systems(world);
+        let mut triggers = std::mem::take(&mut self.triggers);
+        self.init_triggers(world, entity, &mut triggers);
+
+        let mut errs = ErrList::default();
+        self.load_triggers_into(&mut triggers, &mut data.into_iter(), world, &mut errs);
+        self.triggers = triggers;
+        errs.into()
+    }
+
+    /// Loads data into the given triggers, in the order of [`StateMachine::save_triggers_of`]
+    #[cfg(feature = "serde")]
+    fn load_triggers_into(
+        &self,
+        triggers: &mut Triggers,
+        data: &mut impl Iterator<Item = Option<serde_json::Value>>,
+        world: &World,
+        errs: &mut ErrList,
+    ) {
+        for (edge, data) in self.transitions.iter().zip(&mut *data) {
+            if let Some(data) = data {
+                errs.push(triggers.triggers[edge.trigger].load(data, world));
+            }
+        }
+
+        for (state, sub_machine) in self.sub_machines() {
+            if let Some(triggers) = triggers.sub_machines.get_mut(&state.id) {
+                sub_machine.load_triggers_into(triggers, data, world, errs);
+            }
+        }
+
+        for (region, triggers) in self.regions.iter().zip(&mut triggers.regions) {
+            region.load_triggers_into(triggers, data, world, errs);
+        }
+    }
+
+    /// Collects the functions that start updating entities' `StateMachineInfo` when they enter or
+    /// leave this machine's states, its sub-machines', or its regions'
+    #[cfg(feature = "reflect")]
+    pub(crate) fn state_watchers(&self, watchers: &mut Vec<fn(&mut World)>) {
+        watchers.extend(self.states.values().map(|metadata| metadata.watch));
+
+        for sub_machine in self.sub_machines.values() {
+            sub_machine.state_watchers(watchers);
+        }
+
+        for region in &self.regions {
+            region.state_watchers(watchers);
+        }
+    }
+
+    /// Finds the state that the entity is in. Returns `None` if it's in none of this machine's
+    /// states.
+    fn current(&self, entity: EntityRef) -> Result<Option<TypeId>> {
+        let mut states = self.states.keys();
+        let current = states.find(|&&state| entity.contains_type_id(state));
+
+        let Some(&current) = current else {
+            return Ok(None);
+        };
+
+        if let Some(&other) = states.find(|&&state| entity.contains_type_id(state)) {
+            let entity = entity.id();
+            let state = self.states[&current].name;
+            let other = self.states[&other].name;
+            return Err(MachineError::MultipleStates {
+                entity,
+                states: [state, other],
+            }
+            .into());
+        }
+
+        Ok(Some(current))
+    }
+
+    /// The state that the given entity is in, out of this machine's own states (not its
+    /// sub-machines' or regions'). Returns `None` if it isn't in exactly one of them. To get an
+    /// `EntityRef`, query for it alongside the machine, like `Query<(EntityRef, &StateMachine)>`.
+    pub fn current_state(&self, entity: EntityRef) -> Option<CurrentState> {
+        let current = self.current(entity).ok()??;
+        Some(self.current_state_info(entity, current))
+    }
+
+    /// Every state that the given entity is in: this machine's, then its active sub-machine's, and
+    /// so on, and then its regions'. See [`StateMachine::current_state`].
+    pub fn current_states(&self, entity: EntityRef) -> Vec<CurrentState> {
+        let mut states = Vec::new();
+        self.collect_current_states(entity, &mut states);
+        states
+    }
+
+    fn collect_current_states(&self, entity: EntityRef, states: &mut Vec<CurrentState>) {
+        if let Ok(Some(current)) = self.current(entity) {
+            states.push(self.current_state_info(entity, current));
+
+            if let Some(sub_machine) = self.sub_machines.get(&current) {
+                sub_machine.collect_current_states(entity, states);
+            }
+        }
+
+        for region in &self.regions {
+            region.collect_current_states(entity, states);
+        }
+    }
+
+    fn state_info(&self, id: TypeId) -> StateInfo {
+        StateInfo {
+            id,
+            name: self.states[&id].name,
+        }
+    }
+
+    fn current_state_info(&self, entity: EntityRef, current: TypeId) -> CurrentState {
+        CurrentState {
+            id: current,
+            name: self.states[&current].name,
+            elapsed: entity
+                .get::<StateTimer>()
+                .and_then(|timer| timer.elapsed_by_id(current)),
+        }
+    }
+
+    /// Checks this machine's and its regions' triggers, without changing the world. `triggers` are
+    /// the entity's triggers of this machine. `parent` is the parent state if this is a
+    /// sub-machine.
+    fn check(
+        &self,
+        world: &World,
+        entity: Entity,
+        triggers: &mut Triggers,
+        parent: Option<StateInfo>,
+        errs: &mut ErrList,
+    ) -> Pending {
+        Pending {
+            // A machine that only holds regions has no states of its own
+            step: if self.states.is_empty() {
+                Step::Stay
+            } else {
+                let step = self.check_transitions(world, entity, triggers, parent, errs);
+                errs.push(step).unwrap_or_default()
+            },
+            regions: self
+                .regions
+                .iter()
+                .zip(&mut triggers.regions)
+                .map(|(region, triggers)| region.check(world, entity, triggers, parent, errs))
+                .collect(),
+        }
+    }
+
+    /// Checks all transitions until one is triggered. If none are, checks the active sub-machine.
+    fn check_transitions(
+        &self,
+        world: &World,
+        entity: Entity,
+        triggers: &mut Triggers,
+        parent: Option<StateInfo>,
+        errs: &mut ErrList,
+    ) -> Result<Step> {
+        let Some(current) = self.current(world.entity(entity))? else {
+            // If the parent state was entered without entering this machine (such as when the
+            // entity is spawned in the parent state), enter the initial state
+            return match parent {
+                Some(parent) => Ok(Step::Enter(parent)),
+                None => Err(MachineError::NoState { entity }.into()),
+            };
+        };
+
+        // The machine is complete
+        if self.final_states.contains_key(&current) {
+            return Ok(Step::Stay);
+        }
+
+        let entity_history = world.get::<StateHistory>(entity);
+        let mut triggered = Vec::new();
+        let mut triggered_priority = None;
+
+        for (index, edge) in self.transitions.iter().enumerate() {
+            if !edge.source.matches(current) {
+                continue;
+            }
+
+            // Transitions are sorted by priority, so the rest have lower priorities
+            if let Some(priority) = triggered_priority {
+                if self.selection == Selection::First || edge.priority < priority {
+                    break;
+                }
+            }
+
+            // History transitions are only taken if there's a state to return to
+            if let Target::History(history) = edge.transition.target() {
+                if find_history(&self.states, entity_history, history).is_none() {
+                    continue;
+                }
+            }
+
+            let out = triggers.triggers[edge.trigger]
+                .check(world, entity)
+                .map_err(|err| MachineError::Trigger {
+                    entity,
+                    trigger: edge.trigger_name.clone(),
+                    error: err.to_string(),
+                })?;
+
+            if let Some(out) = out {
+                triggered.push((index, out));
+                triggered_priority = Some(edge.priority);
+            }
+        }
+
+        match self.selection {
+            Selection::First => (),
+            // Transitions that can't be chosen don't count as triggered
+            Selection::Weighted => {
+                triggered.retain(|&(index, _)| self.transitions[index].weight > 0.)
+            }
+            Selection::Utility {
+                threshold,
+                hysteresis,
+            } => {
+                triggered = self
+                    .best(current, triggered, threshold, hysteresis)
+                    .into_iter()
+                    .collect();
+            }
+        }
+
+        if triggered.len() > 1 {
+            return Ok(Step::Choose { current, triggered });
+        }
+
+        if let Some((index, out)) = triggered.pop() {
+            return Ok(Step::Trans {
+                current,
+                index,
+                out,
+            });
+        }
+
+        // Transitions of this machine take priority over the sub-machine's
+        let parent = self.state_info(current);
+        let sub_machine = self
+            .sub_machines
+            .get(&current)
+            .zip(triggers.sub_machines.get_mut(&current));
+        Ok(match sub_machine {
+            Some((sub_machine, triggers)) => Step::Sub {
+                current,
+                pending: Box::new(sub_machine.check(world, entity, triggers, Some(parent), errs)),
+            },
+            None => Step::Stay,
+        })
+    }
+
+    /// Runs the on-update systems of the state that the entity is in, and then its active
+    /// sub-machine's and its regions'
+    fn run_updates(&mut self, world: &mut World, entity: Entity) -> Result {
+        let mut errs = ErrList::default();
+
+        // An on-update system may have despawned the entity
+        let Ok(entity_ref) = world.get_entity(entity) else {
+            return OK;
+        };
+
+        // Being in no state or many states is reported by `transition`
+        if let Ok(Some(current)) = self.current(entity_ref) {
+            for (matches, system) in &mut self.on_update {
+                if matches.matches(current) {
+                    errs.push(system.run(entity, world));
+                }
+            }
+
+            if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+                errs.push(sub_machine.run_updates(world, entity));
+            }
+        }
+
+        for region in &mut self.regions {
+            errs.push(region.run_updates(world, entity));
+        }
+
+        errs.into()
+    }
+
+    /// Whether this machine, one of its sub-machines, or one of its regions has on-update systems
+    fn has_updates(&self) -> bool {
+        !self.on_update.is_empty()
+            || self.sub_machines.values().any(Self::has_updates)
+            || self.regions.iter().any(Self::has_updates)
+    }
+
+    /// Takes the transitions found by `check`
+    fn apply(&mut self, world: &mut World, entity: Entity, pending: Pending) -> Result {
+        let mut errs = ErrList::default();
+
+        errs.push(match pending.step {
+            Step::Stay => OK,
+            Step::Enter(parent) => self.enter_initial(world, entity, parent, &[]),
+            Step::Trans {
+                current,
+                index,
+                out,
+            } => self.take(world, entity, current, index, out),
+            Step::Choose { current, triggered } => match self.choose(world, triggered) {
+                Some((index, out)) => self.take(world, entity, current, index, out),
+                None => OK,
+            },
+            Step::Sub { current, pending } => match self.sub_machines.get_mut(&current) {
+                Some(sub_machine) => sub_machine.apply(world, entity, *pending),
+                None => OK,
+            },
+        });
+
+        for (region, pending) in self.regions.iter_mut().zip(pending.regions) {
+            if despawned(world, entity) {
+                break;
+            }
+
+            errs.push(region.apply(world, entity, pending));
+        }
+
+        errs.into()
+    }
+
+    /// Finds the triggered transition with the highest score, for [`Selection::Utility`]. Returns
+    /// `None` if the entity should stay in its state.
+    fn best(
+        &self,
+        current: TypeId,
+        triggered: Vec<(usize, Box<dyn Any + Send>)>,
+        threshold: f32,
+        hysteresis: f32,
+    ) -> Option<(usize, Box<dyn Any + Send>)> {
+        // Whether the transition goes to the current state
+        let stays = |index: usize| match self.transitions[index].transition.target() {
+            Target::State(next) => next == current,
+            Target::History(_) => false,
+        };
+
+        let (index, out, _) = triggered
+            .into_iter()
+            .filter_map(|(index, out)| {
+                let Score(mut score) = out.downcast_ref().copied().unwrap_or_default();
+                if stays(index) {
+                    score += hysteresis;
+                }
+
+                (score >= threshold).then_some((index, out, score))
+            })
+            .reduce(|best, next| if next.2 > best.2 { next } else { best })?;
+
+        (!stays(index)).then_some((index, out))
+    }
+
+    /// Chooses one of the triggered transitions by weight, using the [`TransitionRng`]
+    fn choose(
+        &self,
+        world: &mut World,
+        triggered: Vec<(usize, Box<dyn Any + Send>)>,
+    ) -> Option<(usize, Box<dyn Any + Send>)> {
+        let weights = triggered
+            .iter()
+            .map(|&(index, _)| self.transitions[index].weight.max(0.))
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<f32>();
+        if total <= 0. {
+            return None;
+        }
+
+        let mut roll = world.get_resource_or_init::<TransitionRng>().0.f32() * total;
+        let chosen = weights
+            .iter()
+            .position(|&weight| {
+                roll -= weight;
+                roll < 0.
+            })
+            // In case of rounding error
+            .or_else(|| weights.iter().rposition(|&weight| weight > 0.))?;
+
+        triggered.into_iter().nth(chosen)
+    }
+
+    /// Takes the transition at the given index. Logs the transition and runs `on_enter/on_exit`
+    /// triggers.
+    fn take(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        current: TypeId,
+        index: usize,
+        out: Box<dyn Any + Send>,
+    ) -> Result {
+        // An earlier transition may have changed this entity's state, or despawned it, since it was
+        // checked
+        if !world
+            .get_entity(entity)
+            .is_ok_and(|entity| entity.contains_type_id(current))
+        {
+            return OK;
+        }
+
+        let (next_state, restored) = match self.transitions[index].transition.target() {
+            Target::State(next_state) => (next_state, None),
+            Target::History(history) => {
+                let entity_history = world.get::<StateHistory>(entity);
+                let Some(entry) = find_history(&self.states, entity_history, history) else {
+                    return OK;
+                };
+
+                (entry.value.state_id(), Some((entry.clone(), history)))
+            }
+        };
+
+        if world.entity(entity).contains::<StateHistory>() {
+            if let Some(entry) = self.snapshot(world, entity, current) {
+                world.get_mut::<StateHistory>(entity).unwrap().push(entry);
+            }
+        }
+
+        let transition = &mut self.transitions[index].transition;
+        let from = self.states[&current].name;
+        let to = self.states[&next_state].name;
+        let change = StateChange {
+            entity,
+            prev: StateInfo {
+                id: current,
+                name: from,
+            },
+            next: StateInfo {
+                id: next_state,
+                name: to,
+            },
+        };
+        let mut errs = ErrList::default();
+
+        if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+            errs.push(sub_machine.exit(world, entity, change.next));
+        }
+
+        errs.push(run_events(&mut self.on_exit, change, world));
+        if despawned(world, entity) {
+            return errs.into();
+        }
+
+        let taken =
+            transition
+                .take(world, entity, current, out)
+                .map_err(|err| MachineError::Transition {
+                    entity,
+                    error: err.to_string(),
+                });
+        if errs.push(taken).is_none() || despawned(world, entity) {
+            return errs.into();
+        }
+
+        if let Some((entry, _)) = &restored {
+            entry.value.insert(&mut world.entity_mut(entity));
+        }
+
+        if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+            timer.exit(current);
+            timer.enter(next_state);
+        }
+
+        errs.push(run_events(&mut self.on_enter, change, world));
+        if despawned(world, entity) {
+            return errs.into();
+        }
+
+        let transitioned = Transitioned {
+            entity,
+            prev: current,
+            prev_name: from,
+            next: next_state,
+            next_name: to,
+            index,
+        };
+
+        if let Some(mut messages) = world.get_resource_mut::<Messages<Transitioned>>() {
+            messages.write(transitioned.clone());
+        }
+
+        world.commands().trigger(transitioned);
+
+        if self.log_transitions {
+            info!("{entity:?} transitioned from {from} to {to}");
+        }
+
+        self.complete(world, entity, change.next);
+
+        self.reinit.insert(entity);
+
+        if let Some(sub_machine) = self.sub_machines.get_mut(&next_state) {
+            let sub_history = match &restored {
+                Some((entry, History::Deep(_))) => &entry.sub[..],
+                _ => &[],
+            };
+
+            errs.push(sub_machine.enter(world, entity, change.prev, sub_history));
+        }
+
+        errs.into()
+    }
+
+    /// Clones the given state out of the entity, along with the states that its sub-machine is in
+    fn snapshot(&self, world: &World, entity: Entity, state: TypeId) -> Option<HistoryEntry> {
+        Some(HistoryEntry {
+            value: (self.states[&state].snapshot)(world.entity(entity))?,
+            sub: self
+                .sub_machines
+                .get(&state)
+                .map(|sub_machine| sub_machine.snapshot_all(world, entity))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Clones the states that this machine and its regions are in out of the entity
+    fn snapshot_all(&self, world: &World, entity: Entity) -> Vec<HistoryEntry> {
+        let mut entries = Vec::new();
+
+        if let Ok(Some(current)) = self.current(world.entity(entity)) {
+            entries.extend(self.snapshot(world, entity, current));
+        }
+
+        for region in &self.regions {
+            entries.extend(region.snapshot_all(world, entity));
+        }
+
+        entries
+    }
+
+    /// Enters the initial states of this sub-machine and its regions. `prev` is the state that the
+    /// parent machine transitioned from. States in `history` are restored instead of the initial
+    /// states, for deep history transitions.
+    fn enter(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        prev: StateInfo,
+        history: &[HistoryEntry],
+    ) -> Result {
+        let mut errs = ErrList::default();
+
+        if !self.states.is_empty() {
+            errs.push(self.enter_initial(world, entity, prev, history));
+        }
+
+        for region in &mut self.regions {
+            if despawned(world, entity) {
+                break;
+            }
+
+            errs.push(region.enter(world, entity, prev, history));
+        }
+
+        errs.into()
+    }
+
+    /// Enters this sub-machine's initial state, and its sub-machine's, and so on. If one of this
+    /// machine's states is in `history`, restores that instead.
+    fn enter_initial(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        prev: StateInfo,
+        history: &[HistoryEntry],
+    ) -> Result {
+        let restored = history
+            .iter()
+            .find(|entry| self.states.contains_key(&entry.value.state_id()));
+
+        let (value, sub_history) = match (restored, &self.initial) {
+            (Some(entry), _) => (&*entry.value, &entry.sub[..]),
+            (None, Some(initial)) => (&**initial, &[][..]),
+            (None, None) => {
+                return Err(MachineError::NoInitialState { entity }.into());
+            }
+        };
+        let next_state = value.state_id();
+
+        value.insert(&mut world.entity_mut(entity));
+
+        if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+            timer.enter(next_state);
+        }
+
+        let next = StateInfo {
+            id: next_state,
+            name: self.states[&next_state].name,
+        };
+        let change = StateChange { entity, prev, next };
+        let mut errs = ErrList::default();
+        errs.push(run_events(&mut self.on_enter, change, world));
+        if despawned(world, entity) {
+            return errs.into();
+        }
+
+        if self.log_transitions {
+            info!("{entity:?} entered {}", next.name);
+        }
+
+        self.complete(world, entity, next);
+
+        self.reinit.insert(entity);
+
+        if let Some(sub_machine) = self.sub_machines.get_mut(&next_state) {
+            errs.push(sub_machine.enter(world, entity, prev, sub_history));
+        }
+
+        errs.into()
+    }
+
+    /// Removes all of this machine's states from the entity, including its sub-machines' and
+    /// regions', and enters the initial states, without running any events. See
+    /// [`ErrorPolicy::Reset`].
+    fn reset(&mut self, world: &mut World, entity: Entity) -> Result {
+        self.remove_all(world, entity);
+        self.insert_initial(world, entity)
+    }
+
+    fn remove_all(&self, world: &mut World, entity: Entity) {
+        for &state in self.states.keys() {
+            if let Some(component) = world.components().get_id(state) {
+                world.entity_mut(entity).remove_by_id(component);
+            }
+
+            if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+                timer.exit(state);
+            }
+        }
+
+        for machine in self.sub_machines.values().chain(&self.regions) {
+            machine.remove_all(world, entity);
+        }
+    }
+
+    fn insert_initial(&mut self, world: &mut World, entity: Entity) -> Result {
+        let mut errs = ErrList::default();
+
+        if !self.states.is_empty() {
+            let Some(initial) = &self.initial else {
+                return Err(MachineError::NoInitialState { entity }.into());
+            };
+            let state = initial.state_id();
+
+            initial.insert(&mut world.entity_mut(entity));
+
+            if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+                timer.enter(state);
+            }
+
+            self.reinit.insert(entity);
+
+            if let Some(sub_machine) = self.sub_machines.get_mut(&state) {
+                errs.push(sub_machine.insert_initial(world, entity));
+            }
+        }
+
+        for region in &mut self.regions {
+            errs.push(region.insert_initial(world, entity));
+        }
+
+        errs.into()
+    }
+
+    /// Removes whichever of this sub-machine's states are active, including its regions', innermost
+    /// first. `next` is the state that the parent machine is transitioning to.
+    fn exit(&mut self, world: &mut World, entity: Entity, next: StateInfo) -> Result {
+        let mut errs = ErrList::default();
+
+        for region in &mut self.regions {
+            errs.push(region.exit(world, entity, next));
+        }
+
+        if !despawned(world, entity) {
+            errs.push(self.exit_current(world, entity, next));
+        }
+        errs.into()
+    }
+
+    /// Removes this sub-machine's active state, after exiting its sub-machine
+    fn exit_current(&mut self, world: &mut World, entity: Entity, next: StateInfo) -> Result {
+        let Ok(entity_ref) = world.get_entity(entity) else {
+            return OK;
+        };
+        let Some(current) = self.current(entity_ref)? else {
+            return OK;
+        };
+
+        let mut errs = ErrList::default();
+        if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+            errs.push(sub_machine.exit(world, entity, next));
+        }
+
+        let prev = self.state_info(current);
+        errs.push(run_events(
+            &mut self.on_exit,
+            StateChange { entity, prev, next },
+            world,
+        ));
+        if despawned(world, entity) {
+            return errs.into();
+        }
+
+        let component = world.components().get_id(current).unwrap();
+        world.entity_mut(entity).remove_by_id(component);
+
+        if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+            timer.exit(current);
+        }
+
+        if self.log_transitions {
+            info!("{entity:?} exited {}", prev.name);
+        }
+
+        self.reinit.insert(entity);
+
+        errs.into()
+    }
+
+    /// The machine with the given state, out of this machine and its active sub-machines and
+    /// regions
+    fn find_active(&mut self, entity: EntityRef, state: TypeId) -> Option<&mut StateMachine> {
+        if self.states.contains_key(&state) {
+            return Some(self);
+        }
+
+        if let Ok(Some(current)) = self.current(entity) {
+            if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+                if let Some(machine) = sub_machine.find_active(entity, state) {
+                    return Some(machine);
+                }
+            }
+        }
+
+        self.regions
+            .iter_mut()
+            .find_map(|region| region.find_active(entity, state))
+    }
+
+    /// Moves the entity to the given state, running events like a transition would. See
+    /// [`TransitionCommands::transition_to`].
+    fn force(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        value: &dyn StateValue,
+        name: &str,
+    ) -> Result {
+        // The events' systems may not have been initialized yet
+        self.init_all_systems(world);
+
+        let next_state = value.state_id();
+        let machine = self
+            .find_active(world.entity(entity), next_state)
+            .ok_or_else(|| {
+                format!(
+                    "{entity} can't transition to {name}, since it isn't a state of its machine \
+                    or of an active sub-machine or region"
+                )
+            })?;
+
+        external::paused(world, |world| machine.force_here(world, entity, value))
+    }
+
+    /// Finds the states that the entity must leave before this machine is replaced by one with
+    /// `states`, without changing anything. Fails if the entity's states can't be found.
+    #[cfg(feature = "asset")]
+    fn plan_reload(&self, entity: EntityRef, states: &HashSet<TypeId>) -> Result<Reload> {
+        let mut regions = Vec::new();
+        for (index, region) in self.regions.iter().enumerate() {
+            if let Some(current) = region.current(entity)? {
+                if region.in_removed(entity, states) {
+                    regions.push((index, current));
+                }
+            }
+        }
+
+        Ok(Reload {
+            current: self.current_removed(entity, states)?,
+            regions,
+        })
+    }
+
+    /// Moves the entity out of the states found by [`StateMachine::plan_reload`], before this
+    /// machine is replaced when its asset is reloaded. If the entity's state, or a state of its
+    /// active sub-machine, was removed, it moves to `fallback` like
+    /// [`TransitionCommands::transition_to`]. Regions whose active states were removed are exited.
+    #[cfg(feature = "asset")]
+    fn reload(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        reload: Reload,
+        fallback: Option<&dyn StateValue>,
+    ) -> Result {
+        // The events' systems may not have been initialized yet
+        self.init_all_systems(world);
+        let mut errs = ErrList::default();
+
+        if let (Some(current), Some(fallback)) = (reload.current, fallback) {
+            // The fallback may be new
+            if !self.states.contains_key(&fallback.state_id()) {
+                *self = fallback.register(std::mem::take(self));
+            }
+
+            errs.push(external::paused(world, |world| {
+                self.change_state(
+                    world,
+                    entity,
+                    Some(current),
+                    fallback.state_id(),
+                    Some(fallback),
+                )
+            }));
+        }
+
+        for (index, current) in reload.regions {
+            // There's no next state, so the region leaves for its own state
+            let region = &mut self.regions[index];
+            let next = region.state_info(current);
+            errs.push(external::paused(world, |world| {
+                region.exit(world, entity, next)
+            }));
+        }
+
+        errs.into()
+    }
+
+    /// The entity's state, if it or a state of its active sub-machine isn't in `states`
+    #[cfg(feature = "asset")]
+    fn current_removed(
+        &self,
+        entity: EntityRef,
+        states: &HashSet<TypeId>,
+    ) -> Result<Option<TypeId>> {
+        Ok(self.current(entity)?.filter(|current| {
+            !states.contains(current)
+                || self
+                    .sub_machines
+                    .get(current)
+                    .is_some_and(|sub_machine| sub_machine.in_removed(entity, states))
+        }))
+    }
+
+    /// Whether the entity is in a state of this machine, its active sub-machine, or its regions
+    /// that `states` doesn't have
+    #[cfg(feature = "asset")]
+    fn in_removed(&self, entity: EntityRef, states: &HashSet<TypeId>) -> bool {
+        matches!(self.current_removed(entity, states), Ok(Some(_)))
+            || self
+                .regions
+                .iter()
+                .any(|region| region.in_removed(entity, states))
+    }
+
+    /// Collects the states of this machine, its sub-machines, and its regions
+    #[cfg(feature = "asset")]
+    fn collect_states(&self, states: &mut HashSet<TypeId>) {
+        states.extend(self.states.keys());
+
+        for machine in self.sub_machines.values().chain(&self.regions) {
+            machine.collect_states(states);
+        }
+    }
+
+    /// Enters the initial states of this machine and its regions, if the entity is in none of their
+    /// states. See [`StateMachine::initial`].
+    fn start(&mut self, world: &mut World, entity: Entity) -> Result {
+        if !self.has_initial() {
+            return OK;
+        }
+
+        // The events' systems may not have been initialized yet
+        self.init_all_systems(world);
+        external::paused(world, |world| self.start_here(world, entity))
+    }
+
+    fn has_initial(&self) -> bool {
+        self.initial.is_some() || self.regions.iter().any(Self::has_initial)
+    }
+
+    fn start_here(&mut self, world: &mut World, entity: Entity) -> Result {
+        let mut errs = ErrList::default();
+
+        if let (Some(initial), Ok(None)) = (&self.initial, self.current(world.entity(entity))) {
+            let initial = self.state_info(initial.state_id());
+            errs.push(self.enter_initial(world, entity, initial, &[]));
+        }
+
+        for region in &mut self.regions {
+            if despawned(world, entity) {
+                break;
+            }
+
+            errs.push(region.start_here(world, entity));
+        }
+
+        errs.into()
+    }
+
+    /// [`StateMachine::force`] for a state of this machine
+    fn force_here(&mut self, world: &mut World, entity: Entity, value: &dyn StateValue) -> Result {
+        // If the entity is in none of this machine's states, there's nothing to exit
+        let current = self.current(world.entity(entity))?;
+        self.change_state(world, entity, current, value.state_id(), Some(value))
+    }
+
+    /// Moves the entity from `current` to `next`, running events like a transition would. Inserts
+    /// `value` if it's given. Otherwise, `next` must already be inserted.
+    fn change_state(
+        &mut self,
+        world: &mut World,
+        entity: Entity,
+        current: Option<TypeId>,
+        next: TypeId,
+        value: Option<&dyn StateValue>,
+    ) -> Result {
+        let next = self.state_info(next);
+        let mut errs = ErrList::default();
+        let change = current.map(|current| StateChange {
+            entity,
+            prev: self.state_info(current),
+            next,
+        });
+
+        if let Some(change) = change {
+            let current = change.prev.id;
+
+            if world.entity(entity).contains::<StateHistory>() {
+                if let Some(entry) = self.snapshot(world, entity, current) {
+                    world.get_mut::<StateHistory>(entity).unwrap().push(entry);
+                }
+            }
+
+            if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+                errs.push(sub_machine.exit(world, entity, next));
+            }
+
+            errs.push(run_events(&mut self.on_exit, change, world));
+            if despawned(world, entity) {
+                return errs.into();
+            }
+
+            let component = world.components().get_id(current).unwrap();
+            world.entity_mut(entity).remove_by_id(component);
+
+            if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+                timer.exit(current);
+            }
+        }
+
+        if let Some(value) = value {
+            value.insert(&mut world.entity_mut(entity));
+        }
+
+        if let Some(mut timer) = world.get_mut::<StateTimer>(entity) {
+            timer.enter(next.id);
+        }
+
+        if let Some(change) = change {
+            errs.push(run_events(&mut self.on_enter, change, world));
+            if despawned(world, entity) {
+                return errs.into();
+            }
+        }
+
+        if self.log_transitions {
+            match change {
+                Some(change) => info!(
+                    "{entity:?} was moved from {} to {}",
+                    change.prev.name, next.name
+                ),
+                None => info!("{entity:?} was moved to {}", next.name),
+            }
+        }
+
+        self.complete(world, entity, next);
+        self.reinit.insert(entity);
+
+        if let (Some(change), Some(sub_machine)) = (change, self.sub_machines.get_mut(&next.id)) {
+            errs.push(sub_machine.enter(world, entity, change.prev, &[]));
+        }
+
+        errs.into()
+    }
+
+    /// If the entity just entered a final state, inserts its `Done` marker and triggers
+    /// [`Completed`]. See [`StateMachine::final_state`].
+    fn complete(&self, world: &mut World, entity: Entity, state: StateInfo) {
+        let Some(&done) = self.final_states.get(&state.id) else {
+            return;
+        };
+
+        if let Some(done) = done {
+            world.entity_mut(entity).insert((done, KeepDone));
+        }
+
+        let completed = Completed {
+            entity,
+            state: state.id,
+            state_name: state.name,
+            done,
+        };
+
+        if let Some(mut messages) = world.get_resource_mut::<Messages<Completed>>() {
+            messages.write(completed.clone());
+        }
+
+        world.commands().trigger(completed);
+
+        if self.log_transitions {
+            info!("{entity:?} completed in {}", state.name);
+        }
+    }
+
+    /// Whether external changes to the state must be recorded, because it belongs to this machine,
+    /// one of its sub-machines, or one of its regions, and that machine doesn't ignore them
+    pub(crate) fn watches(&self, state: TypeId) -> bool {
+        (self.external_changes != ExternalChanges::Ignore && self.states.contains_key(&state))
+            || self
+                .sub_machines
+                .values()
+                .chain(&self.regions)
+                .any(|machine| machine.watches(state))
+    }
+
+    /// Handles the changes to the entity's states that weren't made by this machine, by its
+    /// [`ExternalChanges`] policy, and then its active sub-machine's and its regions'
+    fn reconcile(&mut self, world: &mut World, entity: Entity, records: &[Record]) -> Result {
+        let mut errs = ErrList::default();
+
+        if self.external_changes != ExternalChanges::Ignore {
+            errs.push(self.reconcile_here(world, entity, records));
+        }
+
+        // An event may have despawned the entity
+        let Ok(entity_ref) = world.get_entity(entity) else {
+            return errs.into();
+        };
+
+        if let Ok(Some(current)) = self.current(entity_ref) {
+            if let Some(sub_machine) = self.sub_machines.get_mut(&current) {
+                errs.push(sub_machine.reconcile(world, entity, records));
+            }
+        }
+
+        for region in &mut self.regions {
+            errs.push(region.reconcile(world, entity, records));
+        }
+
+        errs.into()
+    }
+
+    /// [`StateMachine::reconcile`] for the states of this machine
+    fn reconcile_here(&mut self, world: &mut World, entity: Entity, records: &[Record]) -> Result {
+        let records = records
+            .iter()
+            .filter(|record| self.states.contains_key(&record.state_id()))
+            .collect::<Vec<_>>();
+        if records.is_empty() {
+            return OK;
+        }
+
+        // Work out which states the entity was in by undoing the changes, last first
+        let entity_ref = world.entity(entity);
+        let after = self
+            .states
+            .keys()
+            .copied()
+            .filter(|&state| entity_ref.contains_type_id(state))
+            .collect::<Vec<_>>();
+        let mut before = after.clone();
+        for record in records.iter().rev() {
+            match record {
+                Record::Entered(state) => before.retain(|other| other != state),
+                Record::Left(value) => {
+                    if !before.contains(&value.state_id()) {
+                        before.push(value.state_id());
+                    }
+                }
+            }
+        }
+
+        // Entering a state while in none, like when the entity is spawned, isn't a change. Neither
+        // is replacing a state's value.
+        if before.is_empty()
+            || before.len() == after.len() && before.iter().all(|state| after.contains(state))
+        {
+            return OK;
+        }
+
+        let names = |states: &[TypeId]| match states {
+            [] => "no state".to_string(),
+            states => states
+                .iter()
+                .map(|state| self.states[state].name)
+                .collect::<Vec<_>>()
+                .join(" and "),
+        };
+        let moved = format!(
+            "{entity} was moved from {} to {} outside of its state machine",
+            names(&before),
+            names(&after),
+        );
+
+        match self.external_changes {
+            ExternalChanges::Ignore => OK,
+            ExternalChanges::Error => Err(moved.into()),
+            ExternalChanges::Revert => {
+                for &state in after.iter().filter(|state| !before.contains(state)) {
+                    let component = world.components().get_id(state).unwrap();
+                    world.entity_mut(entity).remove_by_id(component);
+                }
+
+                for &state in before.iter().filter(|state| !after.contains(state)) {
+                    // The state's value from before the first change
+                    let value = records.iter().find_map(|record| match record {
+                        Record::Left(value) if value.state_id() == state => Some(value),
+                        _ => None,
+                    });
+
+                    if let Some(value) = value {
+                        value.insert(&mut world.entity_mut(entity));
+                    }
+                }
+
+                OK
+            }
+            ExternalChanges::Adopt => {
+                let entered = after
+                    .iter()
+                    .copied()
+                    .filter(|state| !before.contains(state))
+                    .collect::<Vec<_>>();
+                let (&[prev], &[next]) = (&before[..], &entered[..]) else {
+                    return Err(format!("{moved}, so there's no one state to adopt").into());
+                };
+
+                // The previous state is still inserted if the next state was inserted alongside it
+                self.change_state(world, entity, Some(prev), next, None)
+            }
+        }
+    }
+}
+
+/// A state machine definition that may be shared by many entities. Give [`StateMachineDef::new`] a
+/// [`StateMachine`] built as usual, and insert clones of the definition into entities instead of
+/// `StateMachine`s. The transitions and events are stored and initialized once for every entity
+/// that uses the definition, rather than once per entity. Each entity's state is still stored on
+/// the entity.
+///
+/// Triggers aren't shared. Each entity gets its own, cloned from the trigger that was given to the
+/// machine, so triggers with local state (such as a `Local` or `time_in_state`'s timer) work like
+/// they do in a `StateMachine`. Builders, actions, and event and on-update systems are shared, and
+/// so is any local state in them.
+#[derive(Component, Clone)]
+#[component(on_add = start_machine, on_replace = forget_triggers)]
+#[cfg_attr(feature = "reflect", require(crate::reflect::StateMachineInfo))]
+pub struct StateMachineDef(Arc<SharedMachine>);
+
+/// The contents of a [`StateMachineDef`]
+struct SharedMachine {
+    /// Written while a machine that uses the definition runs, and read while its triggers are
+    /// checked
+    machine: RwLock<StateMachine>,
+    /// The triggers of each entity that uses the definition, which are pulled out while the machine
+    /// runs. They have their own lock, since entities may stop using the definition while the
+    /// machine is locked.
+    triggers: Mutex<EntityHashMap<Triggers>>,
+    /// The machine's settings, which never change, so they can be read without locking it
+    max_iterations: Option<u32>,
+    error_policy: Option<ErrorPolicy>,
+}
+
+impl StateMachineDef {
+    /// Creates a definition from a machine. Each entity that uses the definition gets its own
+    /// triggers, built from the machine's transitions.
+    pub fn new(mut machine: StateMachine) -> Self {
+        // Each entity builds its own
+        machine.triggers = default();
+
+        Self(Arc::new(SharedMachine {
+            max_iterations: machine.max_iterations,
+            error_policy: machine.error_policy,
+            machine: RwLock::new(machine),
+            triggers: default(),
+        }))
+    }
+
+    /// See [`StateMachine::current_state`]. Fails if the definition is in use, like
+    /// [`StateMachineDef::machine`].
+    pub fn current_state(&self, entity: EntityRef) -> Result<Option<CurrentState>> {
+        Ok(self.try_read()?.current_state(entity))
+    }
+
+    /// See [`StateMachine::current_states`]. Fails if the definition is in use, like
+    /// [`StateMachineDef::machine`].
+    pub fn current_states(&self, entity: EntityRef) -> Result<Vec<CurrentState>> {
+        Ok(self.try_read()?.current_states(entity))
+    }
+
+    /// Locks the shared machine so that it can be inspected. Machines that use this definition
+    /// can't run while the lock is held, so don't hold onto it. Fails instead of waiting if a
+    /// machine that uses the definition is taking a transition or running its on-update systems,
+    /// such as when this is called from one of the definition's own events. Triggers may call this.
+    pub fn machine(&self) -> Result<impl Deref<Target = StateMachine> + '_> {
+        self.try_read()
+    }
+
+    fn try_read(&self) -> Result<RwLockReadGuard<'_, StateMachine>> {
+        let Self(shared) = self;
+        match shared.machine.try_read() {
+            Ok(machine) => Ok(machine),
+            // A panic while the machine was locked doesn't leave it in an invalid state
+            Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                Err("the `StateMachineDef` is in use by a running machine".into())
+            }
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, StateMachine> {
+        let Self(shared) = self;
+        shared
+            .machine
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, StateMachine> {
+        let Self(shared) = self;
+        shared
+            .machine
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_triggers(&self) -> MutexGuard<'_, EntityHashMap<Triggers>> {
+        let Self(shared) = self;
+        shared
+            .triggers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the entity uses this definition
+    fn used_by(&self, entity: EntityRef) -> bool {
+        entity
+            .get::<StateMachineDef>()
+            .is_some_and(|def| Arc::ptr_eq(&def.0, &self.0))
+    }
+}
+
+/// Extension trait for moving entities between states through their state machine
+pub trait TransitionCommands {
+    /// Moves the entity to the given state through its `StateMachine` or `StateMachineDef`, rather
+    /// than inserting the state directly. Like a transition, this removes the entity's current
+    /// state, runs matching on-exit and on-enter events, updates its `StateTimer` and
+    /// `StateHistory`, exits and enters sub-machines, and is logged if the machine has
+    /// `StateMachine::set_trans_logging`. Triggers and actions don't run, and no `Transitioned`
+    /// event is triggered. The state may belong to the machine, or to an active sub-machine or
+    /// region. If it's issued while the machine runs, such as by one of its events' systems, the
+    /// entity moves once the machine is done.
+    fn transition_to(&mut self, state: impl Clone + Component) -> &mut Self;
+}
+
+impl TransitionCommands for EntityCommands<'_> {
+    fn transition_to(&mut self, state: impl Clone + Component) -> &mut Self {
+        self.queue(move |entity: EntityWorldMut| force_state(entity, state))
+    }
+}
+
+fn force_state<S: Clone + Component>(entity: EntityWorldMut, state: S) -> Result {
+    run_machine_command(entity, move |entity| {
+        let id = entity.id();
+        let name = type_name::<S>();
+
+        with_entity_machine(entity, |machine, world| {
+            machine.force(world, id, &state, name)
+        })
+        .unwrap_or_else(|| {
+            Err(format!("{id} can't transition to {name}, since it has no state machine").into())
+        })
+    })
+}
+
+/// How many times `transition` has run, so triggers like `frames_in_state` can count frames
+#[derive(Resource, Default)]
+pub(crate) struct MachineRuns(pub(crate) u64);
+
+/// Commands for entities' machines that were issued while the machines were pulled out of the world,
+/// such as by an event's system. They're run once the machines are back.
+#[derive(Resource, Default)]
+struct RunningMachines(Vec<Box<dyn FnOnce(&mut World) -> Result + Send + Sync>>);
+
+/// Runs `command` on the entity's machine now, or once the machines are back in the world if
+/// they're running
+fn run_machine_command(
+    entity: EntityWorldMut,
+    command: impl FnOnce(EntityWorldMut) -> Result + Send + Sync + 'static,
+) -> Result {
+    let id = entity.id();
+    let world = entity.into_world_mut();
+
+    let Some(mut running) = world.get_resource_mut::<RunningMachines>() else {
+        return command(world.entity_mut(id));
+    };
+
+    running.0.push(Box::new(move |world| {
+        // The entity may have been despawned since
+        match world.get_entity_mut(id) {
+            Ok(entity) => command(entity),
+            Err(_) => OK,
+        }
+    }));
+
+    OK
+}
+
+/// The states that an entity must leave when its machine is reloaded. See
+/// [`StateMachine::plan_reload`].
+#[cfg(feature = "asset")]
+struct Reload {
+    /// The entity's state, if it or a state of its active sub-machine was removed
+    current: Option<TypeId>,
+    /// The index of each region whose active state was removed, and that state
+    regions: Vec<(usize, TypeId)>,
+}
+
+/// Replaces the entity's `StateMachine` with `machine`, after moving the entity out of the states
+/// that `machine` doesn't have. If there's no `fallback` for a removed state, the entity keeps its
+/// old machine. See `MachineAsset`.
+#[cfg(feature = "asset")]
+pub(crate) fn reload_machine(
+    mut entity: EntityWorldMut,
+    mut machine: StateMachine,
+    fallback: Option<Box<dyn StateValue>>,
+) -> Result {
+    let id = entity.id();
+    let mut states = HashSet::new();
+    machine.collect_states(&mut states);
+
+    // Nothing changes unless the whole migration can be done
+    let old = entity
+        .get::<StateMachine>()
+        .ok_or("the entity has no `StateMachine`")?;
+    let reload = old.plan_reload(entity.as_readonly(), &states)?;
+    if fallback.is_none() {
+        if let Some(current) = reload.current {
+            return Err(format!(
+                "{id} is in {}, which was removed from its state machine, and the machine has no \
+                fallback state",
+                old.states[&current].name,
+            )
+            .into());
+        }
+    }
+
+    // Pull the old machine out of the entity while it runs, like `transition` does
+    let mut old = std::mem::take(&mut *entity.get_mut::<StateMachine>().unwrap());
+    let world = entity.into_world_mut();
+    let mut errs = ErrList::default();
+
+    errs.push(old.reload(world, id, reload, fallback.as_deref()));
+
+    // Enter the initial states of the regions that were exited
+    errs.push(machine.start(world, id));
+    world.entity_mut(id).insert(machine);
+
+    // The machine's states and transitions changed, so describe them again
+    #[cfg(feature = "reflect")]
+    crate::reflect::refresh(world, id);
+
+    errs.into()
+}
+
+/// Enters the initial states of a machine that was just added to an entity
+fn start_machine(mut world: DeferredWorld, context: HookContext) {
+    world
+        .commands()
+        .entity(context.entity)
+        .queue(|entity: EntityWorldMut| {
+            run_machine_command(entity, |entity| {
+                let id = entity.id();
+                with_entity_machine(entity, |machine, world| machine.start(world, id)).unwrap_or(OK)
+            })
+        });
+}
+
+/// Forgets the triggers of an entity that stops using a definition
+fn forget_triggers(world: DeferredWorld, context: HookContext) {
+    if let Some(def) = world.get::<StateMachineDef>(context.entity) {
+        def.lock_triggers().remove(&context.entity);
+    }
+}
+
+/// Calls `f` with the entity's `StateMachine` or `StateMachineDef`, if it has one
+fn with_entity_machine<T>(
+    mut entity: EntityWorldMut,
+    f: impl FnOnce(&mut StateMachine, &mut World) -> T,
+) -> Option<T> {
+    let id = entity.id();
+
+    if let Some(def) = entity.get::<StateMachineDef>().cloned() {
+        let world = entity.into_world_mut();
+        let mut machine = def.write();
+        let out = f(&mut machine, world);
+
+        // The entity's triggers aren't built until the machine first runs
+        let triggers = def.lock_triggers().remove(&id);
+        if let Some(mut triggers) = triggers {
+            machine.reinit_triggers(world, id, &mut triggers);
+            def.lock_triggers().insert(id, triggers);
+        }
+
+        return Some(out);
+    }
+
+    let mut machine = entity.get_mut::<StateMachine>()?;
+
+    // Pull the machine out of the entity while it runs, like `transition` does
+    let mut machine = std::mem::take(&mut *machine);
+    let world = entity.into_world_mut();
+    let out = f(&mut machine, world);
+
+    let mut triggers = std::mem::take(&mut machine.triggers);
+    machine.reinit_triggers(world, id, &mut triggers);
+    machine.triggers = triggers;
+
+    if let Some(mut slot) = world.get_mut::<StateMachine>(id) {
+        *slot = machine;
+    }
+
+    Some(out)
+}
+
+/// A unit of work for the `transition` system
+// Boxing the `StateMachine` would allocate for every machine on every run
+#[allow(clippy::large_enum_variant)]
+enum Machines {
+    /// A `StateMachine` that was pulled out of its entity, with the entity and the machine's
+    /// triggers
+    Owned(StateMachine, (Entity, Triggers)),
+    /// A `StateMachineDef` and every entity that uses it, with the entity's triggers
+    Shared(StateMachineDef, Vec<(Entity, Triggers)>),
+}
+
+impl Machines {
+    /// See [`StateMachine::run_to_completion`]
+    fn max_iterations(&self) -> Option<u32> {
+        match self {
+            Self::Owned(machine, _) => machine.max_iterations,
+            Self::Shared(def, _) => def.0.max_iterations,
+        }
+    }
+
+    /// The machine, locked into `guard` if it's shared, and the entities that use it with their
+    /// triggers
+    fn parts<'a: 'g, 'g>(
+        &'a mut self,
+        guard: &'g mut Option<RwLockWriteGuard<'a, StateMachine>>,
+    ) -> (&'g mut StateMachine, &'a mut [(Entity, Triggers)]) {
+        match self {
+            Self::Owned(machine, entity) => (machine, std::slice::from_mut(entity)),
+            Self::Shared(def, entities) => (guard.insert(def.write()), entities),
+        }
+    }
+
+    /// Initializes the machine's transitions and systems
+    fn init_systems(&mut self, world: &mut World) {
+        match self {
+            Self::Owned(machine, _) => machine.init_all_systems(world),
+            Self::Shared(def, _) => def.write().init_all_systems(world),
+        }
+    }
+
+    /// Initializes the machine, and the triggers of the entities in this pass. See [`in_pass`].
+    fn init(&mut self, world: &mut World, unsettled: Option<&[Entity]>) {
+        let mut guard = None;
+        let (machine, entities) = self.parts(&mut guard);
+        machine.init_all_systems(world);
+
+        for (entity, triggers) in entities {
+            if in_pass(unsettled, *entity) {
+                machine.init_triggers(world, *entity, triggers);
+            }
+        }
+
+        machine.clear_reinit(&|entity| in_pass(unsettled, entity));
+    }
+
+    /// Checks the triggers of the entities in this pass. See [`in_pass`].
+    fn check(
+        &mut self,
+        world: &World,
+        unsettled: Option<&[Entity]>,
+    ) -> Vec<(Entity, Pending, ErrList)> {
+        let mut guard = None;
+        let (machine, entities) = self.parts(&mut guard);
+
+        entities
+            .iter_mut()
+            .filter(|(entity, _)| in_pass(unsettled, *entity))
+            .map(|(entity, triggers)| {
+                let mut errs = ErrList::default();
+                let pending = machine.check(world, *entity, triggers, None, &mut errs);
+                (*entity, pending, errs)
+            })
+            .collect()
+    }
+
+    /// Handles the changes to entities' states that weren't made by their machines. See
+    /// [`ExternalChanges`].
+    fn reconcile(
+        &mut self,
+        world: &mut World,
+        records: &EntityHashMap<Vec<Record>>,
+        errs: &mut EntityErrs,
+    ) {
+        let mut guard = None;
+        let (machine, entities) = self.parts(&mut guard);
+
+        for (entity, triggers) in entities {
+            let Some(records) = records.get(entity) else {
+                continue;
+            };
+
+            if world.get_entity(*entity).is_ok() {
+                errs.push(*entity, machine.reconcile(world, *entity, records));
+                machine.reinit_triggers(world, *entity, triggers);
+            }
+        }
+    }
+
+    /// Runs the on-update systems of every entity's states
+    fn update(&mut self, world: &mut World, errs: &mut EntityErrs) {
+        let mut guard = None;
+        let (machine, entities) = self.parts(&mut guard);
+
+        for &mut (entity, _) in entities {
+            errs.push(entity, machine.run_updates(world, entity));
+        }
+    }
+
+    fn apply(
+        &mut self,
+        world: &mut World,
+        checked: Vec<(Entity, Pending, ErrList)>,
+        errs: &mut EntityErrs,
+    ) {
+        let mut guard = None;
+        let (machine, entities) = self.parts(&mut guard);
+        // `checked` is in the same order as `entities`
+        let mut entities = entities.iter_mut();
+
+        for (entity, pending, check_errs) in checked {
+            errs.extend(entity, check_errs.0);
+
+            // An earlier transition may have despawned this entity
+            if world.get_entity(entity).is_err() {
+                continue;
+            }
+
+            let moves = pending.moves();
+            errs.push(entity, machine.apply(world, entity, pending));
+
+            if moves && world.get_entity(entity).is_ok() {
+                if let Some((_, triggers)) = entities.find(|(other, _)| *other == entity) {
+                    machine.reinit_triggers(world, entity, triggers);
+                }
+            }
+        }
+    }
+
+    /// The entities that use these machines
+    fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        let entities = match self {
+            Self::Owned(_, entity) => std::slice::from_ref(entity),
+            Self::Shared(_, entities) => entities,
+        };
+
+        entities.iter().map(|&(entity, _)| entity)
+    }
+
+    /// See [`StateMachine::with_error_policy`]
+    fn error_policy(&self) -> Option<ErrorPolicy> {
+        match self {
+            Self::Owned(machine, _) => machine.error_policy,
+            Self::Shared(def, _) => def.0.error_policy,
+        }
+    }
+
+    /// Puts the entity in its machine's initial state. See [`ErrorPolicy::Reset`].
+    fn reset(&mut self, world: &mut World, entity: Entity) -> Result {
+        match self {
+            Self::Owned(machine, _) => machine.reset(world, entity),
+            Self::Shared(def, _) => def.write().reset(world, entity),
+        }
+    }
+}
+
+/// Whether the entity is checked in this pass of `take_transitions`. The first pass checks every
+/// entity, and the passes after it check the `unsettled` entities of machines that run to
+/// completion.
+fn in_pass(unsettled: Option<&[Entity]>, entity: Entity) -> bool {
+    unsettled.is_none_or(|unsettled| unsettled.contains(&entity))
+}
+
+/// The index of the machines that each entity uses, in the list from `borrow_machines`
+fn owners(borrowed_machines: &[Machines]) -> EntityHashMap<usize> {
+    borrowed_machines
+        .iter()
+        .enumerate()
+        .flat_map(|(index, machines)| machines.entities().map(move |entity| (entity, index)))
+        .collect()
+}
+
+/// The entities that will transition
+fn moved(checked: &[(Entity, Pending, ErrList)]) -> Vec<Entity> {
+    checked
+        .iter()
+        .filter(|(_, pending, _)| pending.moves())
+        .map(|&(entity, ..)| entity)
+        .collect()
+}
+
+/// Pulls the machines for which `runs` returns `true` and their triggers out of the world, grouping
+/// the entities that share each definition
+fn borrow_machines(
+    world: &mut World,
+    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
+    def_query: &mut QueryState<(Entity, &StateMachineDef)>,
+    runs: fn(&StateMachine) -> bool,
+) -> Vec<Machines> {
+    world.init_resource::<RunningMachines>();
+
+    // Pull the machines out of the world so we can invoke mutable methods on them. The alternative
+    // would be to wrap the entire `StateMachine` in an `Arc<Mutex>`, but that would complicate the
+    // API surface and you wouldn't be able to do anything more anyway (since you'd need to lock the
+    // mutex anyway).
+    let mut borrowed_machines: Vec<Machines> = machine_query
+        .iter_mut(world)
+        // Skipped machines aren't marked as changed
+        .filter(|(_, machine)| runs(machine))
+        .map(|(entity, mut machine)| {
+            let stub = StateMachine::default();
+            let mut machine = std::mem::replace(machine.as_mut(), stub);
+            let triggers = std::mem::take(&mut machine.triggers);
+            Machines::Owned(machine, (entity, triggers))
+        })
+        .collect();
+
+    // Group the entities that share each definition, so it's only initialized and locked once
+    let mut defs = HashMap::new();
+    let mut skipped_defs = HashMap::new();
+    for (entity, def) in def_query.iter(world) {
+        if *skipped_defs
+            .entry(Arc::as_ptr(&def.0))
+            .or_insert_with(|| !runs(&def.read()))
+        {
+            continue;
+        }
+
+        let stored = def.lock_triggers().remove(&entity);
+        let triggers = stored.unwrap_or_else(|| def.read().new_triggers());
+
+        match defs.entry(Arc::as_ptr(&def.0)) {
+            Entry::Occupied(index) => {
+                if let Machines::Shared(_, entities) = &mut borrowed_machines[*index.get()] {
+                    entities.push((entity, triggers));
+                }
+            }
+            Entry::Vacant(index) => {
+                index.insert(borrowed_machines.len());
+                borrowed_machines.push(Machines::Shared(def.clone(), vec![(entity, triggers)]));
+            }
+        }
+    }
+
+    borrowed_machines
+}
+
+/// Puts the machines and triggers from `borrow_machines` back, and then runs the commands for them
+/// that were issued while they were out
+fn return_machines(world: &mut World, borrowed_machines: Vec<Machines>) -> Result {
+    for machines in borrowed_machines {
+        match machines {
+            Machines::Owned(mut borrowed_machine, (entity, triggers)) => {
+                // Can't use `machine_query` here, since a transition may have added a disabled
+                // component, in which case, we still want to return the state machine
+                let Some(mut machine) = world.get_mut::<StateMachine>(entity) else {
+                    // The `StateMachine` component was removed in a transition
+                    continue;
+                };
+
+                borrowed_machine.triggers = triggers;
+                *machine = borrowed_machine;
+            }
+            Machines::Shared(def, entities) => {
+                let mut stored = def.lock_triggers();
+
+                for (entity, triggers) in entities {
+                    // The entity may have been despawned, or stopped using the definition, in a
+                    // transition
+                    if world
+                        .get_entity(entity)
+                        .is_ok_and(|entity| def.used_by(entity))
+                    {
+                        stored.insert(entity, triggers);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut errs = ErrList::default();
+    if let Some(RunningMachines(commands)) = world.remove_resource::<RunningMachines>() {
+        for command in commands {
+            errs.push(command(world));
+        }
+    }
+
+    errs.into()
+}
+
+/// Runs the on-update systems of every entity's states. See [`StateMachine::on_update`].
+pub(crate) fn on_update(
+    world: &mut World,
+    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
+    def_query: &mut QueryState<(Entity, &StateMachineDef)>,
+    mut logged: Local<EntityHashSet>,
+) -> Result {
+    // The on-update systems' changes to states aren't external
+    external::paused(world, |world| {
+        update_machines(world, machine_query, def_query, &mut logged)
+    })
+}
+
+fn update_machines(
+    world: &mut World,
+    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
+    def_query: &mut QueryState<(Entity, &StateMachineDef)>,
+    logged: &mut EntityHashSet,
+) -> Result {
+    let mut errs = EntityErrs::default();
+    // Most machines have no on-update systems, so they're left in place
+    let mut borrowed_machines =
+        borrow_machines(world, machine_query, def_query, StateMachine::has_updates);
+    let owners = owners(&borrowed_machines);
+
+    for machines in &mut borrowed_machines {
+        machines.init_systems(world);
+        machines.update(world, &mut errs);
+    }
+
+    let mut result = ErrList::default();
+    result.push(handle_errors(
+        world,
+        &mut borrowed_machines,
+        &owners,
+        errs,
+        logged,
+    ));
+    result.push(return_machines(world, borrowed_machines));
+    result.into()
+}
+
+/// Runs all transitions on all entities. Triggers are checked in parallel, and then the
+/// transitions are taken in a deterministic order.
+pub(crate) fn transition(
+    world: &mut World,
+    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
+    def_query: &mut QueryState<(Entity, &StateMachineDef)>,
+    mut logged: Local<EntityHashSet>,
+) -> Result {
+    // The machines' own changes to states aren't external
+    external::paused(world, |world| {
+        take_transitions(world, machine_query, def_query, &mut logged)
+    })
+}
+
+fn take_transitions(
+    world: &mut World,
+    machine_query: &mut QueryState<(Entity, &mut StateMachine)>,
+    def_query: &mut QueryState<(Entity, &StateMachineDef)>,
+    logged: &mut EntityHashSet,
+) -> Result {
+    world.get_resource_or_init::<MachineRuns>().0 += 1;
+
+    let mut errs = EntityErrs::default();
+    let mut borrowed_machines = borrow_machines(world, machine_query, def_query, |_| true);
+    let owners = owners(&borrowed_machines);
+
+    // Handle the changes that were made since the last run, before any triggers are checked
+    let records = external::take_records(world);
+    if !records.is_empty() {
+        for machines in borrowed_machines.iter_mut() {
+            machines.reconcile(world, &records, &mut errs);
+        }
+    }
+
+    // `world` is mutable here, since initialization requires mutating the world
+    for machines in borrowed_machines.iter_mut() {
+        machines.init(world, None);
+    }
+
+    let checked = check_all(world, &mut borrowed_machines);
+
+    // Machines that run to completion, with the entities that transitioned
+    let mut unsettled = Vec::new();
+
+    // Take the transitions one machine at a time, in query order, so the results are deterministic
+    for (index, (machines, checked)) in borrowed_machines.iter_mut().zip(checked).enumerate() {
+        if machines.max_iterations().is_some() {
+            let moved = moved(&checked);
+            if !moved.is_empty() {
+                unsettled.push((index, moved));
+            }
+        }
+
+        machines.apply(world, checked, &mut errs);
+    }
+
+    // Keep checking those machines until they settle. These are usually few, so they're checked
+    // one at a time.
+    let mut iteration = 1;
+    while !unsettled.is_empty() {
+        for (index, entities) in std::mem::take(&mut unsettled) {
+            let machines = &mut borrowed_machines[index];
+            let max_iterations = machines.max_iterations().unwrap_or_default();
+
+            // These entities transitioned in the machine's last iteration, so they aren't checked
+            // again
+            if iteration >= max_iterations {
+                for entity in entities {
+                    let err = MachineError::Loop {
+                        entity,
+                        iterations: max_iterations,
+                    };
+                    errs.push::<()>(entity, Err(err.into()));
+                }
+
+                continue;
+            }
+
+            machines.init(world, Some(&entities));
+            let checked = machines.check(world, Some(&entities));
+            let moved = moved(&checked);
+            if !moved.is_empty() {
+                unsettled.push((index, moved));
+            }
+
+            machines.apply(world, checked, &mut errs);
+        }
+
+        iteration += 1;
+    }
+
+    let mut result = ErrList::default();
+    result.push(handle_errors(
+        world,
+        &mut borrowed_machines,
+        &owners,
+        errs,
+        logged,
+    ));
+    result.push(return_machines(world, borrowed_machines));
+    result.into()
+}
+
+/// Checks every entity's triggers without changing the world, in parallel, even for entities that
+/// share a definition. Returns the results for each of `borrowed_machines`, in order.
+fn check_all(
+    world: &World,
+    borrowed_machines: &mut [Machines],
+) -> Vec<Vec<(Entity, Pending, ErrList)>> {
+    // Lock every definition up front, so their entities can be split between tasks
+    let defs = borrowed_machines
+        .iter()
+        .filter_map(|machines| match machines {
+            Machines::Owned(..) => None,
+            Machines::Shared(def, _) => Some(def.clone()),
+        })
+        .collect::<Vec<_>>();
+    let guards = defs.iter().map(StateMachineDef::read).collect::<Vec<_>>();
+    let mut guards = guards.iter();
+
+    let mut counts = Vec::with_capacity(borrowed_machines.len());
+    let mut entities = Vec::new();
+    for machines in borrowed_machines {
+        let (machine, machine_entities): (&StateMachine, _) = match machines {
+            Machines::Owned(machine, entity) => (machine, std::slice::from_mut(entity)),
+            Machines::Shared(_, entities) => (guards.next().unwrap(), &mut entities[..]),
+        };
+
+        counts.push(machine_entities.len());
+        entities.extend(
+            machine_entities
+                .iter_mut()
+                .map(|(entity, triggers)| (machine, *entity, triggers)),
+        );
+    }
+
+    // The state machines are not in the world, and triggers are read-only, so each entity can be
+    // checked on any task
+    let mut checked = entities
+        .par_splat_map_mut(
+            ComputeTaskPool::get_or_init(TaskPool::default),
+            // Automatically pick the number of tasks
+            None,
+            |_, chunk| {
+                chunk
+                    .iter_mut()
+                    .map(|(machine, entity, triggers)| {
+                        let mut errs = ErrList::default();
+                        let pending = machine.check(world, *entity, triggers, None, &mut errs);
+                        (*entity, pending, errs)
+                    })
+                    .collect::<Vec<_>>()
+            },
+        )
+        .into_iter()
+        .flatten();
+
+    counts
+        .into_iter()
+        .map(|count| checked.by_ref().take(count).collect())
+        .collect()
+}
+
+/// Writes the errors as messages, and handles them by each entity's [`ErrorPolicy`]. Returns the
+/// errors that should be reported.
+fn handle_errors(
+    world: &mut World,
+    borrowed_machines: &mut [Machines],
+    owners: &EntityHashMap<usize>,
+    errs: EntityErrs,
+    logged: &mut EntityHashSet,
+) -> Result {
+    let default_policy = world
+        .get_resource::<ErrorPolicy>()
+        .copied()
+        .unwrap_or_default();
+    let mut report = ErrList::default();
+    let mut failed = EntityHashSet::default();
+
+    for (entity, entity_errs) in errs.into_entities() {
+        failed.insert(entity);
+
+        if let Some(mut messages) = world.get_resource_mut::<Messages<MachineError>>() {
+            messages.write_batch(entity_errs.iter().cloned());
+        }
+
+        let owner = owners.get(&entity).copied();
+        let policy = owner
+            .and_then(|index| borrowed_machines[index].error_policy())
+            .unwrap_or(default_policy);
+
+        match policy {
+            ErrorPolicy::Report => report.extend(entity_errs.into_iter().map(BevyError::from)),
+            ErrorPolicy::LogOnce => {
+                if logged.insert(entity) {
+                    for err in entity_errs {
+                        error!("{err}");
+                    }
+                }
+            }
+            ErrorPolicy::Despawn => {
+                if let Ok(entity) = world.get_entity_mut(entity) {
+                    entity.despawn();
+                }
+            }
+            ErrorPolicy::Reset => {
+                if world.get_entity(entity).is_err() {
+                    continue;
+                }
+
+                let Some(index) = owner else {
+                    report
+                        .push::<(), _>(Err(format!("{entity} can't be reset without its machine")));
+                    continue;
+                };
+
+                let machines = &mut borrowed_machines[index];
+                report.push(external::paused(world, |world| {
+                    machines.reset(world, entity)
+                }));
+            }
+            ErrorPolicy::Panic => {
+                let errs = entity_errs
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                panic!("{}", errs.join("; "));
+            }
+        }
+    }
+
+    // Entities whose machines ran without errors may be logged again
+    logged.retain(|entity| failed.contains(entity));
+
+    report.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+
+    use super::*;
+
+    // Test states to transition between.
+    #[derive(Component, Clone)]
+    struct StateOne;
+    #[derive(Component, Clone)]
+    struct StateTwo;
+    #[derive(Component, Clone)]
+    struct StateThree;
+
+    #[derive(Resource)]
+    struct SomeResource;
+
+    /// Triggers when `SomeResource` is present
+    fn resource_present(res: Option<Res<SomeResource>>) -> bool {
+        res.is_some()
+    }
+
+    #[test]
+    fn test_sets_initial_state() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+        let machine = StateMachine::default().with_state::<StateOne>();
+        let entity = app.world_mut().spawn((machine, StateOne)).id();
+        app.update();
+        // should have moved to state two
+        assert!(
+            app.world().get::<StateOne>(entity).is_some(),
+            "StateMachine should have the initial component"
+        );
+    }
+
+    #[test]
+    fn test_initial() {
+        #[derive(Resource, Default)]
+        struct Entered(u32);
+
+        let mut world = World::new();
+        world.init_resource::<Entered>();
+        let machine = || {
+            StateMachine::default()
+                .trans::<StateOne, _>(always, StateTwo)
+                .initial(StateOne)
+                .on_enter::<StateOne>(|ec| {
+                    ec.commands()
+                        .queue(|world: &mut World| world.resource_mut::<Entered>().0 += 1);
+                })
+        };
+
+        let entity = world.spawn(machine()).id();
+        assert!(world.get::<StateOne>(entity).is_some());
+        assert_eq!(world.resource::<Entered>().0, 1);
+
+        // Entities spawned in a state stay in it
+        let entity = world.spawn((machine(), StateTwo)).id();
+        assert!(world.get::<StateOne>(entity).is_none());
+        assert_eq!(world.resource::<Entered>().0, 1);
+
+        let def = StateMachineDef::new(
+            machine().region(
+                StateMachine::default()
+                    .with_state::<StateThree>()
+                    .initial(StateThree),
+            ),
+        );
+        let entity = world.spawn(def).id();
+        assert!(world.get::<StateOne>(entity).is_some());
+        assert!(world.get::<StateThree>(entity).is_some());
+    }
+
+    #[test]
+    fn test_machine() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .trans::<StateTwo, _>(resource_present, StateThree);
+        let entity = app.world_mut().spawn((machine, StateOne)).id();
+
+        assert!(app.world().get::<StateOne>(entity).is_some());
+
+        app.update();
+        // should have moved to state two
+        assert!(app.world().get::<StateOne>(entity).is_none());
+        assert!(app.world().get::<StateTwo>(entity).is_some());
+
+        app.update();
+        // not yet...
+        assert!(app.world().get::<StateTwo>(entity).is_some());
+        assert!(app.world().get::<StateThree>(entity).is_none());
+
+        app.world_mut().insert_resource(SomeResource);
+        app.update();
+        // okay, *now*
+        assert!(app.world().get::<StateTwo>(entity).is_none());
+        assert!(app.world().get::<StateThree>(entity).is_some());
+    }
+
+    #[test]
+    fn test_many_machines() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let entities = (0..1000)
+            .map(|i| {
+                let machine = StateMachine::default()
+                    .trans::<StateOne, _>(move || i % 2 == 0, StateTwo)
+                    .trans::<StateTwo, _>(always, StateThree);
+                app.world_mut().spawn((machine, StateOne)).id()
+            })
+            .collect::<Vec<_>>();
+
+        app.update();
+        app.update();
+
+        for (i, entity) in entities.into_iter().enumerate() {
+            if i % 2 == 0 {
+                assert!(app.world().get::<StateThree>(entity).is_some());
+            } else {
+                assert!(app.world().get::<StateOne>(entity).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_many_machines_def() {
+        #[derive(Component)]
+        struct Ready;
+
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = || {
+            StateMachine::default()
+                .trans::<StateOne, _>(
+                    |In(entity): In<Entity>, ready: Query<(), With<Ready>>| ready.contains(entity),
+                    StateTwo,
+                )
+                .trans::<StateTwo, _>(always, StateThree)
+        };
+        let def = StateMachineDef::new(machine());
+
+        // Entities that share the definition are checked alongside entities that own their machines
+        let entities = (0..1000)
+            .map(|i| {
+                let mut entity = match i % 3 {
+                    0 => app.world_mut().spawn((machine(), StateOne)),
+                    _ => app.world_mut().spawn((def.clone(), StateOne)),
+                };
+                if i % 2 == 0 {
+                    entity.insert(Ready);
+                }
+                entity.id()
+            })
+            .collect::<Vec<_>>();
+
+        app.update();
+        app.update();
+
+        for (i, entity) in entities.into_iter().enumerate() {
+            if i % 2 == 0 {
+                assert!(app.world().get::<StateThree>(entity).is_some());
+            } else {
+                assert!(app.world().get::<StateOne>(entity).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_machine_def() {
+        #[derive(Component)]
+        struct Ready;
+
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let def = StateMachineDef::new(StateMachine::default().trans::<StateOne, _>(
+            |In(entity): In<Entity>, ready: Query<(), With<Ready>>| ready.contains(entity),
+            StateTwo,
+        ));
+
+        let ready = app.world_mut().spawn((def.clone(), StateOne, Ready)).id();
+        let waiting = app.world_mut().spawn((def.clone(), StateOne)).id();
+
+        app.update();
+        assert!(app.world().get::<StateTwo>(ready).is_some());
+        assert!(app.world().get::<StateOne>(waiting).is_some());
+
+        app.world_mut().entity_mut(waiting).insert(Ready);
+        app.update();
+        assert!(app.world().get::<StateTwo>(waiting).is_some());
+    }
+
+    #[test]
+    fn test_machine_def_triggers() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        // Each entity counts its own checks
+        let def = StateMachineDef::new(StateMachine::default().trans::<StateOne, _>(
+            |mut checks: Local<u32>| {
+                *checks += 1;
+                *checks >= 2
+            },
+            StateTwo,
+        ));
+        let entities = [(); 2].map(|()| app.world_mut().spawn((def.clone(), StateOne)).id());
+
+        app.update();
+        for entity in entities {
+            assert!(app.world().get::<StateOne>(entity).is_some());
+        }
+
+        app.update();
+        for entity in entities {
+            assert!(app.world().get::<StateTwo>(entity).is_some());
+        }
+    }
+
+    #[test]
+    fn test_machine_def_introspection() {
+        #[derive(Resource, Default)]
+        struct InUse(bool);
+
+        let mut app = App::new();
+        app.init_resource::<InUse>().add_systems(Update, transition);
+
+        // Triggers may inspect definitions, but a definition's own events can't while it runs
+        let def = StateMachineDef::new(
+            StateMachine::default()
+                .trans::<StateOne, _>(
+                    |defs: Query<(EntityRef, &StateMachineDef)>| {
+                        defs.iter().any(|(entity, def)| {
+                            def.current_state(entity).is_ok_and(|state| state.is_some())
+                        })
+                    },
+                    StateTwo,
+                )
+                .system_on_enter::<StateTwo, _, _>(
+                    |In(entity): In<Entity>,
+                     defs: Query<(EntityRef, &StateMachineDef)>,
+                     mut in_use: ResMut<InUse>| {
+                        let (entity, def) = defs.get(entity).unwrap();
+                        in_use.0 = def.current_state(entity).is_err();
+                    },
+                ),
+        );
+        let entity = app.world_mut().spawn((def.clone(), StateOne)).id();
+
+        app.update();
+        assert!(app.world().get::<StateTwo>(entity).is_some());
+        assert!(app.world().resource::<InUse>().0);
+        let entity_ref = app.world().entity(entity);
+        assert_eq!(
+            def.current_state(entity_ref).unwrap().unwrap().name,
+            type_name::<StateTwo>()
+        );
+    }
+
+    #[test]
+    fn test_self_transition() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let entity = app
+            .world_mut()
+            .spawn((
+                StateMachine::default().trans::<StateOne, _>(always, StateOne),
+                StateOne,
+            ))
+            .id();
+        app.update();
+        // the sort of bug this is trying to catch: if you insert the new state and then remove the
+        // old state, self-transitions will leave you without the state
+        assert!(
+            app.world().get::<StateOne>(entity).is_some(),
+            "transitioning from a state to itself should work"
+        );
+    }
+
+    #[test]
+    fn test_sub_machine() {
+        #[derive(Component, Clone)]
+        struct Idle;
+        #[derive(Component, Clone)]
+        struct Combat;
+        #[derive(Component, Clone)]
+        struct Approach;
+        #[derive(Component, Clone)]
+        struct Strafe;
+        #[derive(Component, Default)]
+        struct StrafeExited;
+
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let combat = StateMachine::default()
+            .trans::<Approach, _>(always, Strafe)
+            .trans::<Strafe, _>(always, Approach)
+            .on_exit::<Strafe>(|ec| {
+                ec.insert(StrafeExited);
+            });
+
+        let machine = StateMachine::default()
+            .trans::<Combat, _>(resource_present, Idle)
+            .trans::<Idle, _>(resource_present.not(), Combat)
+            .sub_machine::<Combat>(Approach, combat);
+
+        let entity = app.world_mut().spawn((machine, Combat)).id();
+
+        app.update();
+        // spawning in `Combat` enters the sub-machine
+        assert!(app.world().get::<Approach>(entity).is_some());
+
+        app.update();
+        assert!(app.world().get::<Approach>(entity).is_none());
+        assert!(app.world().get::<Strafe>(entity).is_some());
+
+        app.world_mut().insert_resource(SomeResource);
+        app.update();
+        // leaving `Combat` tears down the sub-machine, and the parent transition takes priority
+        assert!(app.world().get::<Idle>(entity).is_some());
+        assert!(app.world().get::<Combat>(entity).is_none());
+        assert!(app.world().get::<Approach>(entity).is_none());
+        assert!(app.world().get::<Strafe>(entity).is_none());
+        assert!(app.world().get::<StrafeExited>(entity).is_some());
+
+        app.world_mut().remove_resource::<SomeResource>();
+        app.update();
+        // entering `Combat` enters the sub-machine's initial state
+        assert!(app.world().get::<Combat>(entity).is_some());
+        assert!(app.world().get::<Approach>(entity).is_some());
+    }
+
+    #[test]
+    fn test_sub_machine_exit_error() {
+        #[derive(Component, Clone)]
+        struct Approach;
+        #[derive(Component, Clone)]
+        struct Strafe;
+        #[derive(Component)]
+        struct Exited;
+
+        let mut world = World::new();
+        let combat = StateMachine::default()
+            .trans::<Approach, _>(always, Strafe)
+            .with_state::<Strafe>();
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .on_exit::<StateOne>(|entity| {
+                entity.insert(Exited);
+            })
+            .sub_machine::<StateOne>(Approach, combat);
+
+        // The sub-machine is in two states, so exiting it fails
+        let entity = world.spawn((machine, StateOne, Approach, Strafe)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        assert!(result.is_err());
+        // The parent still exits its state and takes the transition
+        assert!(world.get::<Exited>(entity).is_some());
+        assert!(world.get::<StateTwo>(entity).is_some());
+    }
+
+    #[test]
+    fn test_sub_machine_exit_and_transition_error() {
+        #[derive(Component, Clone)]
+        struct Approach;
+        #[derive(Component, Clone)]
+        struct Strafe;
 
-    let mut errs = ErrList::default();
+        let mut world = World::new();
+        let combat = StateMachine::default()
+            .trans::<Approach, _>(always, Strafe)
+            .with_state::<Strafe>();
+        let machine = StateMachine::default()
+            .trans_builder(always, |_: Trans<StateOne, _>, _: Res<SomeResource>| {
+                StateTwo
+            })
+            .sub_machine::<StateOne>(Approach, combat);
 
-    // chunk size of None means to automatically pick
-    for &mut (entity, ref mut machine) in &mut borrowed_machines {
-        errs.push(machine.run(world, entity));
+        // Exiting the sub-machine fails, and so does the builder, which is missing its resource
+        let entity = world.spawn((machine, StateOne, Approach, Strafe)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("multiple states"));
+        assert!(err.contains("transition failed"));
+        assert!(world.get::<StateTwo>(entity).is_none());
     }
 
-    // put the borrowed machines back
-    for (entity, borrowed_machine) in borrowed_machines {
-        // Can't use `machine_query` here, since a transition may have added a disabled component,
-        // in which case, we still want to return the state machine
-        let Some(mut machine) = world.get_mut::<StateMachine>(entity) else {
-            // The `StateMachine` component was removed in a transition
-            continue;
+    #[cfg(feature = "asset")]
+    #[test]
+    fn test_reload_failure() {
+        #[derive(Component, Clone)]
+        struct StateFour;
+        #[derive(Component)]
+        struct Exited;
+
+        let mut world = World::new();
+        let region = || {
+            StateMachine::default()
+                .with_state::<StateThree>()
+                .with_state::<StateFour>()
         };
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(resource_present, StateTwo)
+            .on_exit::<StateOne>(|entity| {
+                entity.insert(Exited);
+            })
+            .region(region());
+        // The region is in two states, so its state can't be found
+        let entity = world.spawn((machine, StateOne, StateThree, StateFour)).id();
 
-        *machine = borrowed_machine;
+        // `StateOne` is removed, so the entity would move to the fallback
+        let reloaded = StateMachine::default()
+            .with_state::<StateTwo>()
+            .region(region());
+        let result = reload_machine(world.entity_mut(entity), reloaded, Some(Box::new(StateTwo)));
+        assert!(result.is_err());
+
+        // The entity keeps its old machine and state
+        let entity = world.entity(entity);
+        assert!(entity.contains::<StateOne>());
+        assert!(!entity.contains::<StateTwo>());
+        assert!(!entity.contains::<Exited>());
+        assert_eq!(
+            entity.get::<StateMachine>().unwrap().transitions().count(),
+            1
+        );
     }
 
-    // necessary to actually *apply* the commands we've enqueued
-    // system_state.apply(world);
+    #[test]
+    fn test_despawn_in_event() {
+        #[derive(Component, Clone)]
+        struct StateFour;
 
-    errs.into()
-}
+        fn despawn(In(entity): In<Entity>, mut commands: Commands) {
+            commands.entity(entity).despawn();
+        }
 
-#[cfg(test)]
-mod tests {
-    use bevy::prelude::*;
+        let mut world = World::new();
 
-    use super::*;
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .system_on_exit::<StateOne, _, _>(despawn);
+        let entity = world.spawn((machine, StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get_entity(entity).is_err());
 
-    // Test states to transition between.
-    #[derive(Component, Clone)]
-    struct StateOne;
-    #[derive(Component, Clone)]
-    struct StateTwo;
-    #[derive(Component, Clone)]
-    struct StateThree;
+        // The other regions don't run once the entity is despawned
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .system_on_enter::<StateTwo, _, _>(despawn)
+            .region(StateMachine::default().trans::<StateThree, _>(always, StateFour));
+        let entity = world.spawn((machine, StateOne, StateThree)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get_entity(entity).is_err());
+    }
 
-    #[derive(Resource)]
-    struct SomeResource;
+    #[test]
+    fn test_regions() {
+        #[derive(Component, Clone)]
+        struct Idle;
+        #[derive(Component, Clone)]
+        struct Walk;
+        #[derive(Component, Clone)]
+        struct Holstered;
+        #[derive(Component, Clone)]
+        struct Aiming;
 
-    /// Triggers when `SomeResource` is present
-    fn resource_present(res: Option<Res<SomeResource>>) -> bool {
-        res.is_some()
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .region(StateMachine::default().trans::<Idle, _>(always, Walk))
+            .region(StateMachine::default().trans::<Holstered, _>(resource_present, Aiming));
+
+        let entity = app.world_mut().spawn((machine, Idle, Holstered)).id();
+
+        app.update();
+        // each region transitions independently
+        assert!(app.world().get::<Walk>(entity).is_some());
+        assert!(app.world().get::<Holstered>(entity).is_some());
+
+        app.world_mut().insert_resource(SomeResource);
+        app.update();
+        assert!(app.world().get::<Walk>(entity).is_some());
+        assert!(app.world().get::<Aiming>(entity).is_some());
     }
 
     #[test]
-    fn test_sets_initial_state() {
+    fn test_transitioned() {
+        #[derive(Resource, Default)]
+        struct Observed(Vec<(&'static str, &'static str, usize)>);
+
         let mut app = App::new();
-        app.add_systems(Update, transition);
-        let machine = StateMachine::default().with_state::<StateOne>();
+        app.init_resource::<Observed>()
+            .add_message::<Transitioned>()
+            .add_systems(Update, transition)
+            .add_observer(|event: On<Transitioned>, mut observed: ResMut<Observed>| {
+                observed
+                    .0
+                    .push((event.prev_name, event.next_name, event.index));
+            });
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(resource_present, StateThree)
+            .trans::<StateOne, _>(always, StateTwo);
         let entity = app.world_mut().spawn((machine, StateOne)).id();
+
         app.update();
-        // should have moved to state two
-        assert!(
-            app.world().get::<StateOne>(entity).is_some(),
-            "StateMachine should have the initial component"
+        assert_eq!(
+            app.world().resource::<Observed>().0,
+            [(type_name::<StateOne>(), type_name::<StateTwo>(), 1)],
         );
+
+        let messages = app.world().resource::<Messages<Transitioned>>();
+        let message = messages.iter_current_update_messages().next().unwrap();
+        assert_eq!(message.entity, entity);
+        assert_eq!(message.next, TypeId::of::<StateTwo>());
     }
 
     #[test]
-    fn test_machine() {
+    fn test_action() {
+        #[derive(Resource, Default)]
+        struct Taken(Vec<(Entity, u32)>);
+
+        #[derive(Component, Clone)]
+        struct Count(u32);
+
         let mut app = App::new();
-        app.add_systems(Update, transition);
+        app.init_resource::<Taken>().add_systems(Update, transition);
 
         let machine = StateMachine::default()
-            .trans::<StateOne, _>(
-                Box::new(always.into_trigger()) as Box<dyn EntityTrigger<Out = _>>,
-                StateTwo,
+            .trans_builder(
+                |count: Query<&Count>| count.iter().next().map(|count| count.0),
+                |trans: Trans<StateOne, u32>| Count(trans.out + 1),
             )
-            .trans::<StateTwo, _>(resource_present, StateThree);
+            .with_action(|ctx: TransRef<StateOne, u32>, mut taken: ResMut<Taken>| {
+                taken.0.push((ctx.entity, ctx.out));
+            })
+            .trans::<Count, _>(always, StateOne);
         let entity = app.world_mut().spawn((machine, StateOne)).id();
+        app.world_mut().spawn(Count(1));
 
+        app.update();
+        app.update();
+        // Only the transition with the action ran it
+        assert_eq!(app.world().resource::<Taken>().0, [(entity, 1)]);
         assert!(app.world().get::<StateOne>(entity).is_some());
+    }
 
-        app.update();
-        // should have moved to state two
-        assert!(app.world().get::<StateOne>(entity).is_none());
-        assert!(app.world().get::<StateTwo>(entity).is_some());
+    #[test]
+    fn test_transition_to() {
+        #[derive(Resource, Default)]
+        struct Events(Vec<&'static str>);
+
+        let mut world = World::new();
+        world.init_resource::<Events>();
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always.not(), StateTwo)
+            .with_state::<StateThree>()
+            .system_on_exit::<StateOne, _, _>(|mut events: ResMut<Events>| events.0.push("exit"))
+            .system_on_enter_to::<StateOne, StateThree, _, _>(|mut events: ResMut<Events>| {
+                events.0.push("enter")
+            });
+        let entity = world.spawn((machine, StateOne, StateTimer::default())).id();
+
+        world.commands().entity(entity).transition_to(StateThree);
+        world.flush();
+        assert!(world.get::<StateOne>(entity).is_none());
+        assert!(world.get::<StateThree>(entity).is_some());
+        assert_eq!(world.resource::<Events>().0, ["exit", "enter"]);
+        // The machine was put back
+        assert_eq!(
+            world
+                .get::<StateMachine>(entity)
+                .unwrap()
+                .transitions()
+                .count(),
+            1
+        );
+
+        // States that aren't in the machine can't be entered
+        assert!(force_state(world.entity_mut(entity), Transform::default()).is_err());
+        assert!(world.get::<Transform>(entity).is_none());
+    }
+
+    #[test]
+    fn test_transition_to_in_event() {
+        fn stun(In(entity): In<Entity>, mut commands: Commands) {
+            commands.entity(entity).transition_to(StateThree);
+        }
+
+        let machine = || {
+            StateMachine::default()
+                .trans::<StateOne, _>(always, StateTwo)
+                .with_state::<StateThree>()
+                .system_on_enter::<StateTwo, _, _>(stun)
+        };
+        let mut world = World::new();
+
+        // The forced transition waits until the machine is back on the entity
+        let entity = world.spawn((machine(), StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateTwo>(entity).is_none());
+        assert!(world.get::<StateThree>(entity).is_some());
+
+        // Or until the definition isn't in use
+        let entity = world
+            .spawn((StateMachineDef::new(machine()), StateOne))
+            .id();
+        world.flush();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateTwo>(entity).is_none());
+        assert!(world.get::<StateThree>(entity).is_some());
+    }
+
+    #[test]
+    fn test_on_update() {
+        #[derive(Component, Default)]
+        struct Frames(u32);
+
+        let mut app = App::new();
+        app.add_systems(Update, (on_update, transition).chain());
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(
+                |In(entity): In<Entity>, frames: Query<&Frames>| frames.get(entity).unwrap().0 >= 2,
+                StateTwo,
+            )
+            .on_update::<StateOne, _, _>(
+                |In(entity): In<Entity>, mut frames: Query<&mut Frames>| {
+                    frames.get_mut(entity).unwrap().0 += 1;
+                },
+            );
+        let entity = app
+            .world_mut()
+            .spawn((machine, StateOne, Frames::default()))
+            .id();
 
         app.update();
-        // not yet...
+        assert!(app.world().get::<StateOne>(entity).is_some());
+        app.update();
         assert!(app.world().get::<StateTwo>(entity).is_some());
-        assert!(app.world().get::<StateThree>(entity).is_none());
-
-        app.world_mut().insert_resource(SomeResource);
+        // The system only runs in `StateOne`
         app.update();
-        // okay, *now*
-        assert!(app.world().get::<StateTwo>(entity).is_none());
-        assert!(app.world().get::<StateThree>(entity).is_some());
+        assert_eq!(app.world().get::<Frames>(entity).unwrap().0, 2);
+
+        // Machines without on-update systems are left alone
+        let mut world = World::new();
+        let entity = world.spawn((StateMachine::default(), StateOne)).id();
+        world.clear_trackers();
+        let result: Result = world.run_system_once(on_update).unwrap();
+        result.unwrap();
+        let machine = world.entity(entity).get_ref::<StateMachine>().unwrap();
+        assert!(!machine.is_changed());
     }
 
     #[test]
-    fn test_self_transition() {
+    fn test_run_to_completion() {
         let mut app = App::new();
         app.add_systems(Update, transition);
 
-        let entity = app
-            .world_mut()
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .trans::<StateTwo, _>(always, StateThree)
+            .run_to_completion(4);
+        let entity = app.world_mut().spawn((machine, StateOne)).id();
+        app.update();
+        assert!(app.world().get::<StateThree>(entity).is_some());
+
+        let mut world = World::new();
+        let checks = Arc::new(AtomicU32::new(0));
+        let counted = {
+            let checks = checks.clone();
+            move || {
+                checks.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        };
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(counted.clone(), StateTwo)
+            .trans::<StateTwo, _>(counted, StateOne)
+            .run_to_completion(3);
+        let entity = world.spawn((machine, StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Do its transitions form a loop?"));
+        // The triggers were checked three times, and three transitions were taken
+        assert_eq!(checks.load(Ordering::Relaxed), 3);
+        assert!(world.get::<StateTwo>(entity).is_some());
+    }
+
+    #[test]
+    fn test_external_changes() {
+        #[derive(Resource, Default)]
+        struct Entered;
+
+        let spawn = |world: &mut World, external_changes| {
+            let machine = StateMachine::default()
+                .trans::<StateOne, _>(always.not(), StateTwo)
+                .on_enter::<StateTwo>(|ec| ec.commands().init_resource::<Entered>())
+                .with_external_changes(external_changes);
+            let entity = world.spawn((machine, StateOne)).id();
+            // Spawning in a state isn't a change
+            let result: Result = world.run_system_once(transition).unwrap();
+            result.unwrap();
+
+            world
+                .entity_mut(entity)
+                .remove::<StateOne>()
+                .insert(StateTwo);
+            let result: Result = world.run_system_once(transition).unwrap();
+            (entity, result)
+        };
+
+        let mut world = World::new();
+        let (entity, result) = spawn(&mut world, ExternalChanges::Revert);
+        result.unwrap();
+        assert!(world.get::<StateOne>(entity).is_some());
+        assert!(world.get::<StateTwo>(entity).is_none());
+
+        let mut world = World::new();
+        let (entity, result) = spawn(&mut world, ExternalChanges::Adopt);
+        result.unwrap();
+        assert!(world.get::<StateTwo>(entity).is_some());
+        assert!(
+            world.contains_resource::<Entered>(),
+            "on-enter events should run"
+        );
+
+        // Inserting a state alongside the current one leaves the current one
+        let entity = world
             .spawn((
-                StateMachine::default().trans::<StateOne, _>(always, StateOne),
+                StateMachine::default()
+                    .trans::<StateOne, _>(always.not(), StateTwo)
+                    .with_external_changes(ExternalChanges::Adopt),
                 StateOne,
             ))
             .id();
+        let _: Result = world.run_system_once(transition).unwrap();
+        world.entity_mut(entity).insert(StateTwo);
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateOne>(entity).is_none());
+        assert!(world.get::<StateTwo>(entity).is_some());
+
+        let mut world = World::new();
+        let (entity, result) = spawn(&mut world, ExternalChanges::Error);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("outside of its state machine"));
+        assert!(world.get::<StateTwo>(entity).is_some());
+
+        // Commands queued by the machine's events aren't external changes
+        let mut world = World::new();
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .trans::<StateThree, _>(always.not(), StateOne)
+            .on_enter::<StateTwo>(|ec| {
+                ec.remove::<StateTwo>().insert(StateThree);
+            })
+            .with_external_changes(ExternalChanges::Error);
+        let entity = world.spawn((machine, StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateThree>(entity).is_some());
+
+        // Neither are changes made by on-update systems, even though a definition stays in the world
+        // while they run
+        let mut world = World::new();
+        let machine = StateMachine::default()
+            .trans::<StateTwo, _>(always.not(), StateOne)
+            .on_update::<StateOne, _, _>(|In(entity): In<Entity>, mut commands: Commands| {
+                commands
+                    .entity(entity)
+                    .remove::<StateOne>()
+                    .insert(StateTwo);
+            })
+            .with_external_changes(ExternalChanges::Revert);
+        let entity = world.spawn((StateMachineDef::new(machine), StateOne)).id();
+        let result: Result = world.run_system_once(on_update).unwrap();
+        result.unwrap();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateTwo>(entity).is_some());
+
+        // Changes aren't recorded for machines that ignore them, even when another machine
+        // observes the same states
+        let mut world = World::new();
+        let machine = || StateMachine::default().trans::<StateOne, _>(always.not(), StateTwo);
+        world.spawn((
+            machine().with_external_changes(ExternalChanges::Error),
+            StateOne,
+        ));
+        let entity = world.spawn((machine(), StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        world
+            .entity_mut(entity)
+            .remove::<StateOne>()
+            .insert(StateTwo);
+        assert!(external::take_records(&mut world).is_empty());
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let mut world = World::new();
+        world.init_resource::<Messages<MachineError>>();
+        let machine = || StateMachine::default().trans::<StateOne, _>(always, StateTwo);
+
+        // The entity is in no state
+        let entity = world.spawn(machine()).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MachineError>(),
+            Some(&MachineError::NoState { entity })
+        );
+        let messages = world.resource::<Messages<MachineError>>();
+        let written = messages.iter_current_update_messages().collect::<Vec<_>>();
+        assert_eq!(written, [&MachineError::NoState { entity }]);
+        world.despawn(entity);
+
+        let entity = world
+            .spawn(machine().with_error_policy(ErrorPolicy::LogOnce))
+            .id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        world.despawn(entity);
+
+        // The entity is in multiple states, so it's put back in its initial state
+        let reset = machine()
+            .initial(StateOne)
+            .with_error_policy(ErrorPolicy::Reset);
+        let entity = world.spawn((reset, StateOne, StateTwo)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateOne>(entity).is_some());
+        assert!(world.get::<StateTwo>(entity).is_none());
+        world.despawn(entity);
+
+        // A shared machine keeps its policy for entities that stopped running to completion
+        let def = StateMachineDef::new(
+            machine()
+                .initial(StateOne)
+                .run_to_completion(5)
+                .with_error_policy(ErrorPolicy::Reset),
+        );
+        let failed = world.spawn((def.clone(), StateOne, StateTwo)).id();
+        let moved = world.spawn((def, StateOne)).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateOne>(failed).is_some());
+        assert!(world.get::<StateTwo>(failed).is_none());
+        assert!(world.get::<StateTwo>(moved).is_some());
+        world.despawn(failed);
+        world.despawn(moved);
+
+        world.insert_resource(ErrorPolicy::Despawn);
+        let entity = world.spawn(machine()).id();
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get_entity(entity).is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .trans::<StateOne, _>(always, StateThree)
+            .with_priority(1)
+            .trans::<StateTwo, _>(always, StateOne)
+            .with_priority(-1);
+        let priorities = machine
+            .transitions()
+            .map(|transition| transition.priority)
+            .collect::<Vec<_>>();
+        assert_eq!(priorities, [1, 0, -1]);
+
+        let entity = app.world_mut().spawn((machine, StateOne)).id();
         app.update();
-        // the sort of bug this is trying to catch: if you insert the new state and then remove the
-        // old state, self-transitions will leave you without the state
-        assert!(
-            app.world().get::<StateOne>(entity).is_some(),
-            "transitioning from a state to itself should work"
+        assert!(app.world().get::<StateThree>(entity).is_some());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let run = |seed| {
+            let mut app = App::new();
+            app.insert_resource(TransitionRng::with_seed(seed))
+                .add_systems(Update, transition);
+
+            let entities = (0..100)
+                .map(|_| {
+                    let machine = StateMachine::default()
+                        .with_selection(Selection::Weighted)
+                        .trans::<StateOne, _>(always, StateTwo)
+                        .trans::<StateOne, _>(always, StateThree)
+                        .with_weight(3.)
+                        .trans::<StateOne, _>(always, StateOne)
+                        .with_weight(0.)
+                        // Lower priorities aren't considered
+                        .trans::<StateOne, _>(always, StateOne)
+                        .with_priority(-1)
+                        .with_weight(100.);
+                    app.world_mut().spawn((machine, StateOne)).id()
+                })
+                .collect::<Vec<_>>();
+
+            app.update();
+            entities
+                .into_iter()
+                .map(|entity| {
+                    assert!(app.world().get::<StateOne>(entity).is_none());
+                    app.world().get::<StateThree>(entity).is_some()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let chosen = run(7);
+        let threes = chosen.iter().filter(|&&three| three).count();
+        assert!((50..100).contains(&threes));
+        assert_eq!(run(7), chosen);
+    }
+
+    #[test]
+    fn test_utility() {
+        #[derive(Resource)]
+        struct Needs {
+            hunger: f32,
+            threat: f32,
+        }
+
+        #[derive(Component, Clone)]
+        struct Eat;
+        #[derive(Component, Clone)]
+        struct Flee(f32);
+
+        let mut app = App::new();
+        app.insert_resource(Needs {
+            hunger: 0.5,
+            threat: 0.1,
+        })
+        .add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .with_selection(Selection::Utility {
+                threshold: 0.2,
+                hysteresis: 0.1,
+            })
+            .with_state::<StateOne>()
+            .trans::<AnyState, _>(|needs: Res<Needs>| Score(needs.hunger), Eat)
+            .trans_builder(
+                |needs: Res<Needs>| Score(needs.threat),
+                |trans: Trans<AnyState, Score>| Flee(trans.out.0),
+            )
+            .trans::<Flee, _>(|needs: Res<Needs>| Score(1. - needs.threat), StateOne);
+        let entity = app.world_mut().spawn((machine, StateOne)).id();
+
+        app.update();
+        assert!(app.world().get::<Eat>(entity).is_some());
+
+        // Not enough to overcome the hysteresis
+        app.world_mut().resource_mut::<Needs>().threat = 0.55;
+        app.update();
+        assert!(app.world().get::<Eat>(entity).is_some());
+
+        app.world_mut().resource_mut::<Needs>().threat = 0.7;
+        app.update();
+        assert_eq!(app.world().get::<Flee>(entity).unwrap().0, 0.7);
+
+        // The other scores are below the threshold
+        *app.world_mut().resource_mut::<Needs>() = Needs {
+            hunger: 0.15,
+            threat: 0.05,
+        };
+        app.update();
+        assert!(app.world().get::<StateOne>(entity).is_some());
+    }
+
+    #[test]
+    fn test_utility_unscored() {
+        let utility = || {
+            StateMachine::default().with_selection(Selection::Utility {
+                threshold: 0.5,
+                hysteresis: 0.,
+            })
+        };
+        let mut world = World::new();
+
+        // Triggers that don't output a score have a score of 0, even if they're checked first
+        let machine = utility()
+            .trans::<StateOne, _>(always, StateTwo)
+            .trans::<StateOne, _>(|| Score(1.), StateThree);
+        let scored = world.spawn((machine, StateOne)).id();
+        let machine = utility().trans::<StateOne, _>(always, StateTwo);
+        let unscored = world.spawn((machine, StateOne)).id();
+
+        let result: Result = world.run_system_once(transition).unwrap();
+        result.unwrap();
+        assert!(world.get::<StateThree>(scored).is_some());
+        assert!(world.get::<StateOne>(unscored).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "must be at least 1")]
+    fn test_run_to_completion_zero() {
+        StateMachine::default().run_to_completion(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be positive")]
+    fn test_utility_threshold() {
+        StateMachine::default().with_selection(Selection::Utility {
+            threshold: 0.,
+            hysteresis: 0.1,
+        });
+    }
+
+    #[test]
+    fn test_introspection() {
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(resource_present.not(), StateTwo)
+            .trans::<AnyState, _>(always, StateThree)
+            .trans_history::<StateThree, _>(resource_present, History::Shallow(0))
+            .on_enter::<StateTwo>(|_| {})
+            .command_on_exit::<NotState<StateOne>>(|_: &mut World| {});
+
+        let states = machine.states();
+        assert_eq!(
+            states.iter().map(|state| state.id).collect::<Vec<_>>(),
+            [
+                TypeId::of::<StateOne>(),
+                TypeId::of::<StateThree>(),
+                TypeId::of::<StateTwo>(),
+            ],
         );
+
+        let transitions = machine.transitions().collect::<Vec<_>>();
+        assert_eq!(transitions.len(), 3);
+        assert_eq!(transitions[0].source.name(), type_name::<StateOne>());
+        assert_eq!(
+            transitions[0].target,
+            TransitionTarget::State(StateInfo {
+                id: TypeId::of::<StateTwo>(),
+                name: type_name::<StateTwo>(),
+            }),
+        );
+        assert_eq!(
+            transitions[0].trigger,
+            "not(seldom_state::machine::tests::resource_present)",
+        );
+        assert!(transitions[1].source.matches(TypeId::of::<StateTwo>()));
+        assert_eq!(transitions[1].trigger, "seldom_state::trigger::always");
+        assert_eq!(
+            transitions[2].target,
+            TransitionTarget::History(History::Shallow(0)),
+        );
+
+        let on_enter = machine.on_enter_events().collect::<Vec<_>>();
+        assert_eq!(on_enter.len(), 1);
+        assert_eq!(on_enter[0].to.name(), type_name::<StateTwo>());
+        assert_eq!(on_enter[0].kind, EventKind::Entity);
+
+        let on_exit = machine.on_exit_events().collect::<Vec<_>>();
+        assert!(!on_exit[0].from.matches(TypeId::of::<StateOne>()));
+        assert_eq!(on_exit[0].kind, EventKind::Command);
     }
 
     #[test]
@@ -718,4 +4666,53 @@ mod tests {
         app.update();
         assert!(!app.world().contains_resource::<InBResource>());
     }
+
+    #[test]
+    fn test_final_state() {
+        #[derive(Component, Clone)]
+        struct Quest;
+        #[derive(Component, Clone)]
+        struct Reward;
+        #[derive(Component, Clone)]
+        struct Start;
+        #[derive(Component, Clone)]
+        struct End;
+
+        #[derive(Resource, Default)]
+        struct Completions(Vec<&'static str>);
+
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, StateMachinePlugin::default()))
+            .init_resource::<Completions>()
+            .add_observer(
+                |completed: On<Completed>, mut completions: ResMut<Completions>| {
+                    completions.0.push(completed.state_name);
+                },
+            );
+
+        let quest = StateMachine::default()
+            .trans::<Start, _>(always, End)
+            .trans::<End, _>(always, Start)
+            .final_state::<End>(Some(Done::Success));
+        let machine = StateMachine::default()
+            .trans::<Quest, _>(done(Some(Done::Success)), Reward)
+            .sub_machine::<Quest>(Start, quest)
+            .initial(Quest);
+        let entity = app.world_mut().spawn(machine).id();
+
+        // The sub-machine completes, and stays in its final state
+        app.update();
+        assert!(app.world().get::<End>(entity).is_some());
+        assert_eq!(app.world().get::<Done>(entity), Some(&Done::Success));
+        assert_eq!(
+            app.world().resource::<Completions>().0,
+            [type_name::<End>()]
+        );
+
+        // The parent machine reacts to the sub-machine finishing
+        app.update();
+        assert!(app.world().get::<Reward>(entity).is_some());
+        assert!(app.world().get::<End>(entity).is_none());
+        assert!(app.world().get::<Done>(entity).is_none());
+    }
 }
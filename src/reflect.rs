@@ -0,0 +1,297 @@
+//! Reflection support. See [`StateMachineInfo`].
+
+use std::{any::TypeId, collections::HashSet};
+
+use bevy_ecs::{
+    entity::EntityHashSet, intern::Interned, reflect::ReflectComponent, schedule::ScheduleLabel,
+};
+use bevy_reflect::{std_traits::ReflectDefault, Reflect};
+
+use crate::{introspect::TransitionTarget, prelude::*, set::StateSet};
+
+pub(crate) fn plug(schedule: Interned<dyn ScheduleLabel>) -> impl Fn(&mut App) {
+    move |app| {
+        app.register_type::<StateMachineInfo>()
+            .register_type::<TransitionDescription>()
+            .register_type::<Done>()
+            .register_type::<AnyState>()
+            .init_resource::<StaleInfo>()
+            .add_systems(schedule, update_machine_info.after(StateSet::Transition));
+    }
+}
+
+/// A reflected view of an entity's state machine, for inspectors and scenes. `StateMachine` and
+/// `StateMachineDef` can't be reflected themselves, since they hold triggers and closures, so they
+/// require this component, which is updated after the machines transition, for the entities that
+/// entered or left states. Changing it has no effect on the machine.
+///
+/// `NotState` and `OneOfState` implement `Reflect` for reflected type parameters. Register the ones
+/// that you use, like `app.register_type::<NotState<Idle>>()`.
+#[derive(Component, Reflect, Clone, Debug, Default)]
+#[reflect(Component, Clone, Debug, Default)]
+pub struct StateMachineInfo {
+    /// The name of the machine's definition. See `StateMachine::with_name`.
+    pub name: Option<String>,
+    /// The states that the entity is in, outermost first. See `StateMachine::current_states`.
+    pub current: Vec<String>,
+    /// The states registered in the machine, its sub-machines, and its regions
+    pub states: Vec<String>,
+    /// The machine's transitions, followed by its sub-machines' and regions'
+    pub transitions: Vec<TransitionDescription>,
+}
+
+/// A reflected view of a transition. See `StateMachine::transitions`.
+#[derive(Reflect, Clone, Debug, Default, PartialEq)]
+#[reflect(Clone, Debug, Default, PartialEq)]
+pub struct TransitionDescription {
+    /// The states that the transition may be taken from
+    pub source: String,
+    /// The state that the transition goes to, or the history it restores from
+    pub target: String,
+    /// The trigger's name
+    pub trigger: String,
+}
+
+impl StateMachineInfo {
+    fn new(machine: &StateMachine) -> Self {
+        let mut info = Self {
+            name: machine.name().map(str::to_string),
+            ..default()
+        };
+        info.describe(machine);
+        info
+    }
+
+    /// Adds the states and transitions of the machine, its sub-machines, and its regions
+    fn describe(&mut self, machine: &StateMachine) {
+        self.states.extend(
+            machine
+                .states()
+                .into_iter()
+                .map(|state| state.name.to_string()),
+        );
+        self.transitions.extend(
+            machine
+                .transitions()
+                .map(|transition| TransitionDescription {
+                    source: transition.source.name().to_string(),
+                    target: match transition.target {
+                        TransitionTarget::State(state) => state.name.to_string(),
+                        TransitionTarget::History(history) => format!("{history:?}"),
+                    },
+                    trigger: transition.trigger.into_owned(),
+                }),
+        );
+
+        for (_, sub_machine) in machine.sub_machines() {
+            self.describe(sub_machine);
+        }
+
+        for region in machine.regions() {
+            self.describe(region);
+        }
+    }
+}
+
+/// Entities whose states changed since their `StateMachineInfo` was last updated
+#[derive(Resource, Default)]
+struct StaleInfo {
+    entities: EntityHashSet,
+    /// The state types that are watched
+    watched: HashSet<TypeId>,
+}
+
+/// Starts marking the `StateMachineInfo` of entities that enter or leave `S` as stale, if it isn't
+/// already
+pub(crate) fn watch<S: Component>(world: &mut World) {
+    let mut stale = world.get_resource_or_init::<StaleInfo>();
+    if !stale.watched.insert(TypeId::of::<S>()) {
+        return;
+    }
+
+    world.add_observer(mark_stale::<Insert, S>);
+    world.add_observer(mark_stale::<Remove, S>);
+}
+
+/// Describes the entity's machine again the next time `StateMachineInfo`s are updated, for when its
+/// machine is replaced
+#[cfg(feature = "asset")]
+pub(crate) fn refresh(world: &mut World, entity: Entity) {
+    let Some(mut info) = world.get_mut::<StateMachineInfo>(entity) else {
+        return;
+    };
+
+    *info = default();
+    world
+        .get_resource_or_init::<StaleInfo>()
+        .entities
+        .insert(entity);
+}
+
+fn mark_stale<E: EntityEvent, S: Component>(
+    event: On<E, S>,
+    mut stale: ResMut<StaleInfo>,
+    infos: Query<(), With<StateMachineInfo>>,
+) {
+    if infos.contains(event.event_target()) {
+        stale.entities.insert(event.event_target());
+    }
+}
+
+/// Fills in each new `StateMachineInfo`, and updates the current states of the entities whose
+/// states changed
+pub(crate) fn update_machine_info(
+    world: &mut World,
+    added: &mut QueryState<Entity, Added<StateMachineInfo>>,
+) {
+    let mut entities = world
+        .get_resource_mut::<StaleInfo>()
+        .map(|mut stale| std::mem::take(&mut stale.entities))
+        .unwrap_or_default();
+    entities.extend(added.iter(world));
+
+    let mut watchers = Vec::new();
+    // Entities whose definition is in use, to be updated next time
+    let mut retry = EntityHashSet::default();
+    let updates = entities
+        .into_iter()
+        .filter_map(|entity| {
+            let entity_ref = world.get_entity(entity).ok()?;
+            let info = entity_ref.get::<StateMachineInfo>()?;
+            let describe = |machine: &StateMachine, watchers: &mut Vec<_>| {
+                info.states.is_empty().then(|| {
+                    machine.state_watchers(watchers);
+                    StateMachineInfo::new(machine)
+                })
+            };
+
+            let (current, new) = match entity_ref.get::<StateMachine>() {
+                Some(machine) => (
+                    machine.current_states(entity_ref),
+                    describe(machine, &mut watchers),
+                ),
+                None => {
+                    let def = entity_ref.get::<StateMachineDef>()?;
+                    let (Ok(current), Ok(machine)) =
+                        (def.current_states(entity_ref), def.machine())
+                    else {
+                        retry.insert(entity);
+                        return None;
+                    };
+                    (current, describe(&machine, &mut watchers))
+                }
+            };
+
+            let current = current.iter().map(|state| state.name.to_string()).collect();
+            Some((entity, current, new))
+        })
+        .collect::<Vec<_>>();
+
+    if !retry.is_empty() {
+        world
+            .get_resource_or_init::<StaleInfo>()
+            .entities
+            .extend(retry);
+    }
+
+    for watch in watchers {
+        watch(world);
+    }
+
+    for (entity, current, new) in updates {
+        let Some(mut info) = world.get_mut::<StateMachineInfo>(entity) else {
+            continue;
+        };
+
+        if let Some(new) = new {
+            *info = new;
+        }
+        info.current = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ecs::system::RunSystemOnce;
+
+    use crate::machine::{on_update, transition};
+
+    use super::*;
+
+    #[derive(Component, Reflect, Clone)]
+    struct Idle;
+    #[derive(Component, Clone)]
+    struct Walk;
+
+    #[test]
+    fn test_machine_info() {
+        let mut app = App::new();
+        app.register_type::<NotState<Idle>>()
+            .register_type::<OneOfState<(Idle,)>>()
+            .add_systems(Update, (transition, update_machine_info.after(transition)));
+
+        let machine = StateMachine::default().trans::<Idle, _>(always, Walk);
+        let entity = app.world_mut().spawn((machine, Idle)).id();
+
+        app.update();
+        let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+        assert_eq!(info.current, [std::any::type_name::<Walk>()]);
+
+        // Only entities whose states changed are updated
+        app.world_mut()
+            .get_mut::<StateMachineInfo>(entity)
+            .unwrap()
+            .current
+            .clear();
+        app.update();
+        let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+        assert!(info.current.is_empty());
+
+        app.world_mut()
+            .entity_mut(entity)
+            .remove::<Walk>()
+            .insert(Idle);
+        app.update();
+        let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+        assert_eq!(info.current, [std::any::type_name::<Walk>()]);
+        assert_eq!(info.states.len(), 2);
+        assert_eq!(
+            info.transitions,
+            [TransitionDescription {
+                source: std::any::type_name::<Idle>().to_string(),
+                target: std::any::type_name::<Walk>().to_string(),
+                trigger: "seldom_state::trigger::always".to_string(),
+            }],
+        );
+    }
+
+    #[test]
+    fn test_def_in_use() {
+        let mut app = App::new();
+        app.add_systems(Update, (on_update, update_machine_info).chain());
+
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(always.not(), Walk)
+            .on_update::<Idle, _, _>(|_: In<Entity>, world: &mut World| {
+                // The definition is locked while its on-update systems run
+                world.run_system_once(update_machine_info).unwrap();
+            });
+        let entity = app
+            .world_mut()
+            .spawn((StateMachineDef::new(machine), Walk))
+            .id();
+
+        app.update();
+        let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+        assert_eq!(info.current, [std::any::type_name::<Walk>()]);
+
+        // The entity is updated once the definition isn't in use
+        app.world_mut()
+            .entity_mut(entity)
+            .remove::<Walk>()
+            .insert(Idle);
+        app.update();
+        let info = app.world().get::<StateMachineInfo>(entity).unwrap();
+        assert_eq!(info.current, [std::any::type_name::<Idle>()]);
+    }
+}
@@ -0,0 +1,294 @@
+//! Registering state machine definitions, states, triggers, and events by name, so that machines
+//! can be saved and loaded, and built from data. See [`MachineRegistry`].
+
+use std::{any::TypeId, borrow::Cow, collections::HashMap, sync::Arc};
+
+use bevy_utils::TypeIdMap;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    prelude::*,
+    state::StateValue,
+    trigger::{IntoTrigger, TriggerOut},
+};
+
+/// A trigger built from data. Its output is discarded, since the next state is built from data too.
+pub(crate) struct DataTrigger(Box<dyn CloneTrigger>);
+
+/// An on-enter or on-exit event built from data
+pub(crate) type DataEvent = Arc<dyn Fn(&mut EntityCommands) + Send + Sync>;
+
+/// Registers state machine definitions, states, triggers, and events by name. Machines can't be
+/// serialized themselves, since they hold triggers and closures, so anything that describes a
+/// machine as data refers to these names instead:
+///
+/// - [`MachineRegistry::save`] and [`MachineRegistry::load`] save the runtime data of a machine
+///   built from a registered definition (see [`MachineRegistry::register_machine`])
+/// - [`MachineRegistry::build_data`] builds a machine from a `MachineData`, which refers to
+///   registered states, triggers, and events
+///
+/// Insert this as a resource. Since loading needs both the registry and the world, use
+/// `World::resource_scope` to call [`MachineRegistry::load`].
+#[derive(Resource, Default)]
+pub struct MachineRegistry {
+    pub(crate) machines: HashMap<String, Box<dyn Fn() -> StateMachine + Send + Sync>>,
+    pub(crate) states: HashMap<String, RegisteredState>,
+    pub(crate) names: TypeIdMap<String>,
+    pub(crate) constructors: HashMap<String, Constructor>,
+    pub(crate) triggers: HashMap<String, Box<dyn Fn(Value) -> Result<DataTrigger> + Send + Sync>>,
+    pub(crate) events: HashMap<String, Box<dyn Fn(Value) -> Result<DataEvent> + Send + Sync>>,
+}
+
+/// Type-erased operations on a registered state
+pub(crate) struct RegisteredState {
+    pub(crate) id: TypeId,
+    pub(crate) save: fn(EntityRef) -> Option<Result<Value>>,
+    pub(crate) save_value: fn(&dyn StateValue) -> Result<Value>,
+    pub(crate) load: fn(Value) -> Result<Box<dyn StateValue>>,
+    /// Adds a transition from this state
+    pub(crate) trans_from: fn(StateMachine, DataTrigger, Box<dyn StateValue>) -> StateMachine,
+    pub(crate) on_enter: fn(StateMachine, DataEvent) -> StateMachine,
+    pub(crate) on_exit: fn(StateMachine, DataEvent) -> StateMachine,
+    pub(crate) with_state: fn(StateMachine) -> StateMachine,
+}
+
+/// Builds a state's value from data
+pub(crate) type Constructor = Box<dyn Fn(Value) -> Result<Box<dyn StateValue>> + Send + Sync>;
+
+impl MachineRegistry {
+    /// Registers a state machine definition. `build` is called to build the machine whenever one is
+    /// needed, like in [`MachineRegistry::build`] and [`MachineRegistry::load`].
+    pub fn register_machine(
+        &mut self,
+        name: impl Into<String>,
+        build: impl Fn() -> StateMachine + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.machines.insert(name.into(), Box::new(build));
+        self
+    }
+
+    /// Registers a state type. The name is written to save data and used in `MachineData` in place
+    /// of the type, so it should stay the same as the game is updated. The state's value is
+    /// deserialized from data wherever a state is built, unless a constructor is registered with
+    /// the same name (see [`MachineRegistry::register_constructor`]).
+    pub fn register_state<S: Clone + Component + Serialize + DeserializeOwned>(
+        &mut self,
+        name: impl Into<String>,
+    ) -> &mut Self {
+        let name = name.into();
+        self.names.insert(TypeId::of::<S>(), name.clone());
+        self.states.insert(
+            name,
+            RegisteredState {
+                id: TypeId::of::<S>(),
+                save: |entity| {
+                    entity
+                        .get::<S>()
+                        .map(|state| Ok(serde_json::to_value(state)?))
+                },
+                save_value: |value| {
+                    let state = value
+                        .as_any()
+                        .downcast_ref::<S>()
+                        .ok_or("state value has the wrong type")?;
+                    Ok(serde_json::to_value(state)?)
+                },
+                load: |data| Ok(Box::new(serde_json::from_value::<S>(data)?)),
+                trans_from: |machine, trigger, next| machine.trans_value::<S>(trigger, next),
+                on_enter: |machine, event| machine.on_enter::<S>(move |entity| event(entity)),
+                on_exit: |machine, event| machine.on_exit::<S>(move |entity| event(entity)),
+                with_state: StateMachine::with_state::<S>,
+            },
+        );
+        self
+    }
+
+    /// Registers a constructor, which builds a state from data, like `walk` building
+    /// `Walk { speed }` from a speed. `MachineData` may use the constructor's name wherever it
+    /// uses a state's name. Use `Value::Null` for states built without data.
+    pub fn register_constructor<S: Clone + Component>(
+        &mut self,
+        name: impl Into<String>,
+        construct: impl Fn(Value) -> Result<S> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.constructors.insert(
+            name.into(),
+            Box::new(move |data| Ok(Box::new(construct(data)?))),
+        );
+        self
+    }
+
+    /// Registers a trigger by name, for `MachineData`. `build` builds the trigger from the
+    /// parameters given in the data, or `Value::Null` if there are none. The trigger's output is
+    /// discarded, including any `Score`, so machines built from data can't choose transitions by
+    /// utility.
+    pub fn register_trigger<Marker, T: IntoTrigger<Marker, Trigger: Clone>>(
+        &mut self,
+        name: impl Into<String>,
+        build: impl Fn(Value) -> Result<T> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.triggers.insert(
+            name.into(),
+            Box::new(move |params| Ok(erase_trigger(build(params)?.into_trigger()))),
+        );
+        self
+    }
+
+    /// Registers an on-enter or on-exit event by name, for `MachineData`. `build` builds the event
+    /// from the parameters given in the data, or `Value::Null` if there are none.
+    pub fn register_event<F: Fn(&mut EntityCommands) + Send + Sync + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        build: impl Fn(Value) -> Result<F> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.events.insert(
+            name.into(),
+            Box::new(move |params| Ok(Arc::new(build(params)?) as DataEvent)),
+        );
+        self
+    }
+
+    /// Builds the registered machine with the given name. The machine is named with
+    /// `StateMachine::with_name`, so it can be saved.
+    pub fn build(&self, name: &str) -> Option<StateMachine> {
+        let build = self.machines.get(name)?;
+        Some(build().with_name(name.to_string()))
+    }
+
+    pub(crate) fn name(&self, state: TypeId) -> Result<&str> {
+        Ok(self
+            .names
+            .get(&state)
+            .ok_or_else(|| format!("state {state:?} isn't registered"))?)
+    }
+
+    pub(crate) fn registered(&self, state: TypeId, type_name: &str) -> Result<&RegisteredState> {
+        let name = self
+            .names
+            .get(&state)
+            .ok_or_else(|| format!("state `{type_name}` isn't registered"))?;
+        Ok(&self.states[name])
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<&RegisteredState> {
+        Ok(self
+            .states
+            .get(name)
+            .ok_or_else(|| format!("state `{name}` isn't registered"))?)
+    }
+}
+
+/// Discards a trigger's output, so triggers with different outputs can be stored together
+pub(crate) fn erase_trigger(trigger: impl EntityTrigger + Clone) -> DataTrigger {
+    DataTrigger(Box::new(ErasedTrigger(trigger)))
+}
+
+/// A trigger whose output was discarded, which can still be cloned
+trait CloneTrigger: EntityTrigger<Out = bool> {
+    fn clone_box(&self) -> Box<dyn CloneTrigger>;
+}
+
+struct ErasedTrigger<T>(T);
+
+impl<T: EntityTrigger + Clone> CloneTrigger for ErasedTrigger<T> {
+    fn clone_box(&self) -> Box<dyn CloneTrigger> {
+        let Self(t) = self;
+        Box::new(ErasedTrigger(t.clone()))
+    }
+}
+
+impl<T: EntityTrigger> EntityTrigger for ErasedTrigger<T> {
+    type Out = bool;
+
+    fn init(&mut self, world: &mut World) {
+        let Self(t) = self;
+        t.init(world);
+    }
+
+    fn check(&mut self, entity: Entity, world: &World) -> Result<bool> {
+        let Self(t) = self;
+        Ok(t.check(entity, world)?.into_result().is_ok())
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t) = self;
+        t.name()
+    }
+
+    fn save(&self, world: &World) -> Option<Value> {
+        let Self(t) = self;
+        t.save(world)
+    }
+
+    fn load(&mut self, data: Value, world: &World) -> Result {
+        let Self(t) = self;
+        t.load(data, world)
+    }
+}
+
+impl Clone for DataTrigger {
+    fn clone(&self) -> Self {
+        let Self(t) = self;
+        Self(t.clone_box())
+    }
+}
+
+impl EntityTrigger for DataTrigger {
+    type Out = bool;
+
+    fn init(&mut self, world: &mut World) {
+        let Self(t) = self;
+        t.init(world);
+    }
+
+    fn check(&mut self, entity: Entity, world: &World) -> Result<bool> {
+        let Self(t) = self;
+        t.check(entity, world)
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t) = self;
+        t.name()
+    }
+
+    fn save(&self, world: &World) -> Option<Value> {
+        let Self(t) = self;
+        t.save(world)
+    }
+
+    fn load(&mut self, data: Value, world: &World) -> Result {
+        let Self(t) = self;
+        t.load(data, world)
+    }
+}
+
+/// States and a registry shared by the tests of saving, data, and assets. Each test registers what
+/// else it needs.
+#[cfg(test)]
+pub(crate) mod fixture {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub(crate) struct Idle;
+    #[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub(crate) struct Walk(pub(crate) u32);
+    #[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub(crate) struct Run;
+
+    /// A registry with `idle`, `walk`, and `run` states, and `always` and `frames` triggers
+    pub(crate) fn registry() -> MachineRegistry {
+        let mut registry = MachineRegistry::default();
+        registry
+            .register_state::<Idle>("idle")
+            .register_state::<Walk>("walk")
+            .register_state::<Run>("run")
+            .register_trigger("always", |_| Ok(always))
+            .register_trigger("frames", |params| {
+                Ok(frames_in_state(serde_json::from_value(params)?))
+            });
+        registry
+    }
+}
@@ -0,0 +1,331 @@
+//! Saving and loading state machines' runtime data, like for save games. See
+//! `MachineRegistry::save` and `MachineRegistry::load`.
+
+use std::{any::TypeId, time::Duration};
+
+use bevy_time::Time;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    external,
+    history::{HistoryEntry, StateHistory},
+    prelude::*,
+    state::StateValue,
+    timer::StateTimer,
+    OK,
+};
+
+impl MachineRegistry {
+    /// Saves the runtime data of the given entity's `StateMachine`. The machine must have been built
+    /// by [`MachineRegistry::build`]. Entities that use a `StateMachineDef` can't be saved, and
+    /// return an error.
+    pub fn save(&self, world: &World, entity: Entity) -> Result<SavedMachine> {
+        let entity_ref = world.get_entity(entity)?;
+        if entity_ref.contains::<StateMachineDef>() {
+            return Err(format!("{entity} uses a `StateMachineDef`, which can't be saved").into());
+        }
+
+        let machine = entity_ref
+            .get::<StateMachine>()
+            .ok_or_else(|| format!("{entity} has no `StateMachine`"))?;
+        let name = machine
+            .name()
+            .ok_or("only machines built by `MachineRegistry::build` can be saved")?;
+
+        let states = machine
+            .current_states(entity_ref)
+            .into_iter()
+            .map(|state| {
+                let registered = self.registered(state.id, state.name)?;
+                let value = (registered.save)(entity_ref)
+                    .ok_or_else(|| format!("{entity} is not in {}", state.name))??;
+
+                Ok(SavedState {
+                    state: self.names[&state.id].clone(),
+                    value,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let history = entity_ref
+            .get::<StateHistory>()
+            .map(|history| {
+                Ok::<_, BevyError>(SavedHistory {
+                    capacity: history.capacity(),
+                    entries: history
+                        .entries()
+                        .map(|entry| self.save_entry(entry))
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .transpose()?;
+
+        let timer = entity_ref
+            .get::<StateTimer>()
+            .map(|timer| {
+                Ok::<_, BevyError>(SavedTimer {
+                    since_start: timer.since_start(),
+                    elapsed: timer
+                        .entered()
+                        .map(|state| {
+                            let name = self.name(state)?.to_string();
+                            Ok((name, timer.elapsed_by_id(state).unwrap_or_default()))
+                        })
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .transpose()?;
+
+        Ok(SavedMachine {
+            machine: name.to_string(),
+            states,
+            history,
+            timer,
+            triggers: machine.save_triggers(world),
+        })
+    }
+
+    /// Loads a machine saved by [`MachineRegistry::save`] onto the given entity, building its
+    /// definition again. The entity leaves whatever states of the machine it was in, and enters the
+    /// saved states. Its `StateMachine`, [`StateHistory`], and [`StateTimer`] are replaced. Returns
+    /// an error if the entity uses a `StateMachineDef`.
+    pub fn load(&self, world: &mut World, entity: Entity, saved: SavedMachine) -> Result {
+        if world.get_entity(entity)?.contains::<StateMachineDef>() {
+            return Err(format!("{entity} uses a `StateMachineDef`, which can't be loaded").into());
+        }
+
+        let mut machine = self
+            .build(&saved.machine)
+            .ok_or_else(|| format!("machine `{}` isn't registered", saved.machine))?;
+
+        let mut states = Vec::new();
+        collect_states(&machine, &mut states);
+
+        // Loading isn't an external change to the entity's states
+        external::paused(world, |world| {
+            for state in states {
+                if let Some(component) = world.components().get_id(state) {
+                    world.get_entity_mut(entity)?.remove_by_id(component);
+                }
+            }
+
+            for state in saved.states {
+                let value = self.load_state(state)?;
+                value.insert(&mut world.entity_mut(entity));
+            }
+
+            OK
+        })?;
+
+        if let Some(saved_history) = saved.history {
+            let mut history = StateHistory::new(saved_history.capacity);
+            for entry in saved_history.entries {
+                history.push(self.load_entry(entry)?);
+            }
+            world.entity_mut(entity).insert(history);
+        }
+
+        if let Some(saved_timer) = saved.timer {
+            let now = world
+                .get_resource::<Time>()
+                .map(Time::elapsed)
+                .unwrap_or_default();
+            let elapsed = saved_timer
+                .elapsed
+                .into_iter()
+                .map(|(state, elapsed)| Ok((self.get(&state)?.id, elapsed)))
+                .collect::<Result<Vec<_>>>()?;
+            let timer = StateTimer::restore(now, saved_timer.since_start, elapsed);
+            world.entity_mut(entity).insert(timer);
+        }
+
+        machine.load_triggers(saved.triggers, world, entity)?;
+        world.entity_mut(entity).insert(machine);
+
+        OK
+    }
+
+    fn save_entry(&self, entry: &HistoryEntry) -> Result<SavedEntry> {
+        let id = entry.value.state_id();
+        let registered = self.get(self.name(id)?)?;
+
+        Ok(SavedEntry {
+            state: SavedState {
+                state: self.names[&id].clone(),
+                value: (registered.save_value)(&*entry.value)?,
+            },
+            sub: entry
+                .sub
+                .iter()
+                .map(|entry| self.save_entry(entry))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn load_state(&self, state: SavedState) -> Result<Box<dyn StateValue>> {
+        (self.get(&state.state)?.load)(state.value)
+    }
+
+    fn load_entry(&self, entry: SavedEntry) -> Result<HistoryEntry> {
+        Ok(HistoryEntry {
+            value: self.load_state(entry.state)?,
+            sub: entry
+                .sub
+                .into_iter()
+                .map(|entry| self.load_entry(entry))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Collects the states of the machine, its sub-machines, and its regions
+fn collect_states(machine: &StateMachine, states: &mut Vec<TypeId>) {
+    states.extend(machine.states().into_iter().map(|state| state.id));
+
+    for (_, sub_machine) in machine.sub_machines() {
+        collect_states(sub_machine, states);
+    }
+
+    for region in machine.regions() {
+        collect_states(region, states);
+    }
+}
+
+/// The runtime data of an entity's state machine. See [`MachineRegistry`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedMachine {
+    /// The name of the machine's definition
+    pub machine: String,
+    /// The states that the entity is in, including its sub-machines' and regions' states
+    pub states: Vec<SavedState>,
+    /// The entity's [`StateHistory`], if it has one
+    pub history: Option<SavedHistory>,
+    /// The entity's [`StateTimer`], if it has one
+    pub timer: Option<SavedTimer>,
+    /// The data of each trigger, in the machine's order. `None` for triggers that don't save any.
+    pub triggers: Vec<Option<Value>>,
+}
+
+/// A saved state value
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedState {
+    /// The state's registered name
+    pub state: String,
+    /// The state's value
+    pub value: Value,
+}
+
+/// A saved [`StateHistory`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedHistory {
+    /// How many states are kept
+    pub capacity: usize,
+    /// The recorded states, least recent first
+    pub entries: Vec<SavedEntry>,
+}
+
+/// A state in a [`SavedHistory`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedEntry {
+    /// The state that was exited
+    pub state: SavedState,
+    /// The states that its sub-machine and regions were in
+    pub sub: Vec<SavedEntry>,
+}
+
+/// A saved [`StateTimer`]. Times are saved relative to the time of saving, since the clock may be
+/// different when loaded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedTimer {
+    /// How long it had been since the timer was first updated
+    pub since_start: Option<Duration>,
+    /// How long the entity had been in each state, by registered name
+    pub elapsed: Vec<(String, Duration)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        machine::transition,
+        registry::fixture::{self, Idle, Run, Walk},
+    };
+
+    use super::*;
+
+    fn registry() -> MachineRegistry {
+        let mut registry = fixture::registry();
+        registry.register_machine("walker", || {
+            StateMachine::default()
+                .trans::<Idle, _>(frames_in_state(1), Walk(2))
+                .trans::<Walk, _>(frames_in_state(3), Run)
+        });
+        registry
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut app = App::new();
+        app.insert_resource(registry())
+            .add_systems(Update, transition);
+
+        let machine = app.world().resource::<MachineRegistry>().build("walker");
+        let entity = app
+            .world_mut()
+            .spawn((machine.unwrap(), Idle, StateHistory::new(2)))
+            .id();
+
+        app.update();
+        app.update();
+        app.update();
+        app.update();
+        assert_eq!(app.world().get::<Walk>(entity), Some(&Walk(2)));
+
+        let registry = app.world().resource::<MachineRegistry>();
+        let saved = registry.save(app.world(), entity).unwrap();
+        assert_eq!(saved.machine, "walker");
+        assert_eq!(saved.states[0].state, "walk");
+        assert_eq!(saved.history.as_ref().unwrap().entries.len(), 1);
+
+        // Round trip through text, like a save file
+        let saved = serde_json::to_string(&saved).unwrap();
+        let saved = serde_json::from_str::<SavedMachine>(&saved).unwrap();
+
+        let loaded = app.world_mut().spawn(Idle).id();
+        app.world_mut()
+            .resource_scope(|world, registry: Mut<MachineRegistry>| {
+                registry.load(world, loaded, saved)
+            })
+            .unwrap();
+        assert_eq!(app.world().get::<Walk>(loaded), Some(&Walk(2)));
+        assert!(app.world().get::<Idle>(loaded).is_none());
+        assert_eq!(app.world().get::<StateHistory>(loaded).unwrap().len(), 1);
+
+        // The trigger's frame count was loaded, so both entities run at the same time
+        app.update();
+        assert!(app.world().get::<Run>(entity).is_some());
+        assert!(app.world().get::<Run>(loaded).is_some());
+    }
+
+    #[test]
+    fn test_machine_def() {
+        let mut world = World::new();
+        let registry = registry();
+        let machine = registry.build("walker").unwrap();
+        let entity = world.spawn((StateMachineDef::new(machine), Idle)).id();
+
+        let err = registry.save(&world, entity).unwrap_err();
+        assert!(err.to_string().contains("uses a `StateMachineDef`"));
+
+        let saved = SavedMachine {
+            machine: "walker".to_string(),
+            states: Vec::new(),
+            history: None,
+            timer: None,
+            triggers: Vec::new(),
+        };
+        let err = registry.load(&mut world, entity, saved).unwrap_err();
+        assert!(err.to_string().contains("uses a `StateMachineDef`"));
+        assert!(world.get::<StateMachine>(entity).is_none());
+    }
+}
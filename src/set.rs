@@ -5,6 +5,9 @@ use crate::prelude::*;
 /// System sets used by this crate
 #[derive(Clone, Debug, Eq, Hash, PartialEq, SystemSet)]
 pub enum StateSet {
+    /// Run the on-update systems of entities' states. See `StateMachine::on_update`. Runs before
+    /// `Transition`.
+    OnUpdate,
     /// Do state transitions
     Transition,
     /// Remove `Done` markers
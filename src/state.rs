@@ -1,9 +1,13 @@
 use std::{
+    any::{Any, TypeId},
     fmt::{self, Debug, Formatter},
     marker::PhantomData,
 };
 
-use crate::prelude::*;
+#[cfg(feature = "reflect")]
+use bevy_reflect::Reflect;
+
+use crate::{introspect::StateInfo, prelude::*, trigger::TriggerIn, OK};
 
 use self::sealed::EntityStateSealed;
 
@@ -12,16 +16,33 @@ mod sealed {
 
     use variadics_please::all_tuples;
 
-    use super::AnyState;
+    use super::{AnyState, StateValue};
 
     use crate::prelude::*;
 
     pub trait EntityStateSealed: Sized {
+        /// Whether this is a component, rather than a matcher like `AnyState`
+        const CONCRETE: bool = false;
+
         fn matches(state: TypeId) -> bool;
         fn remove(entity: Entity, world: &mut World, curr: TypeId) -> Self;
+        /// Clones the state out of the entity. Returns `None` for states that aren't components,
+        /// like `AnyState`.
+        fn snapshot(_: EntityRef) -> Option<Box<dyn StateValue>> {
+            None
+        }
+        /// Starts recording changes to the state that weren't made by state machines. Does
+        /// nothing for states that aren't components.
+        fn observe(_: &mut World) {}
+        /// Starts updating the `StateMachineInfo` of entities that enter or leave the state. Does
+        /// nothing for states that aren't components.
+        #[cfg(feature = "reflect")]
+        fn watch(_: &mut World) {}
     }
 
     impl<T: Clone + Component> EntityStateSealed for T {
+        const CONCRETE: bool = true;
+
         fn matches(state: TypeId) -> bool {
             state == TypeId::of::<T>()
         }
@@ -29,6 +50,21 @@ mod sealed {
         fn remove(entity: Entity, world: &mut World, _: TypeId) -> Self {
             world.entity_mut(entity).take::<Self>().unwrap()
         }
+
+        fn snapshot(entity: EntityRef) -> Option<Box<dyn StateValue>> {
+            entity
+                .get::<T>()
+                .map(|state| Box::new(state.clone()) as Box<dyn StateValue>)
+        }
+
+        fn observe(world: &mut World) {
+            crate::external::observe::<T>(world);
+        }
+
+        #[cfg(feature = "reflect")]
+        fn watch(world: &mut World) {
+            crate::reflect::watch::<T>(world);
+        }
     }
 
     impl<T: EntityState> EntityStateSealed for NotState<T> {
@@ -88,7 +124,8 @@ pub trait EntityState: 'static + Clone + Send + Sync + EntityStateSealed {}
 impl<T: Clone + Component> EntityState for T {}
 
 /// State that represents any state other than the given state
-pub struct NotState<T>(PhantomData<T>);
+#[cfg_attr(feature = "reflect", derive(Reflect), reflect(Clone))]
+pub struct NotState<T>(#[cfg_attr(feature = "reflect", reflect(ignore))] PhantomData<T>);
 
 impl<T> Clone for NotState<T> {
     fn clone(&self) -> Self {
@@ -101,7 +138,8 @@ impl<T: EntityState> EntityState for NotState<T> {}
 /// State that represents any of the states given in a tuple (ex
 /// `OneOfState<(Idle, Jump, Attack)>`).
 #[derive(Debug)]
-pub struct OneOfState<T>(PhantomData<T>);
+#[cfg_attr(feature = "reflect", derive(Reflect), reflect(Clone))]
+pub struct OneOfState<T>(#[cfg_attr(feature = "reflect", reflect(ignore))] PhantomData<T>);
 
 impl<T> Clone for OneOfState<T> {
     fn clone(&self) -> Self {
@@ -114,22 +152,132 @@ impl<T: 'static + Send + Sync> EntityState for OneOfState<T> where Self: EntityS
 /// State that represents any state. Transitions from [`AnyState`] may transition from any other
 /// state.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "reflect", derive(Reflect), reflect(Clone, Debug))]
 pub struct AnyState(());
 
 impl EntityState for AnyState {}
 
+/// A type-erased state value, so that it can be stored and inserted later
+pub trait StateValue: Send + Sync {
+    /// The `TypeId` of the state
+    fn state_id(&self) -> TypeId;
+    /// Inserts a clone of the state into the given entity
+    fn insert(&self, entity: &mut EntityWorldMut);
+    /// Clones the state
+    fn clone_value(&self) -> Box<dyn StateValue>;
+    /// The state as `Any`, so it can be downcast
+    fn as_any(&self) -> &dyn Any;
+    /// Registers the state in the given machine. See `StateMachine::with_state`.
+    fn register(&self, machine: StateMachine) -> StateMachine;
+}
+
+impl Debug for dyn StateValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "StateValue")
+    }
+}
+
+impl<T: Clone + Component> StateValue for T {
+    fn state_id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn insert(&self, entity: &mut EntityWorldMut) {
+        entity.insert(self.clone());
+    }
+
+    fn clone_value(&self) -> Box<dyn StateValue> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn register(&self, machine: StateMachine) -> StateMachine {
+        machine.with_state::<T>()
+    }
+}
+
+/// What a transition's builder returns: a state, or a type-erased state value for machines built
+/// from data
+pub(crate) trait NextState: Send + Sync + 'static {
+    /// Inserts the state into the given entity
+    fn insert_next(self, entity: &mut EntityWorldMut);
+}
+
+impl<T: Component> NextState for T {
+    fn insert_next(self, entity: &mut EntityWorldMut) {
+        entity.insert(self);
+    }
+}
+
+impl NextState for Box<dyn StateValue> {
+    fn insert_next(self, entity: &mut EntityWorldMut) {
+        self.insert(entity);
+    }
+}
+
+/// Context for an on-enter or on-exit system. See `StateMachine::system_on_enter`.
+#[derive(Clone, Copy, Debug)]
+pub struct StateChange {
+    /// The entity with the state machine
+    pub entity: Entity,
+    /// The state that the entity left. For a sub-machine's on-enter events, this is the state
+    /// that its parent machine left. For an initial state's on-enter events, this is the initial
+    /// state itself (see `StateMachine::initial`).
+    pub prev: StateInfo,
+    /// The state that the entity entered. For a sub-machine's on-exit events, this is the state
+    /// that its parent machine entered.
+    pub next: StateInfo,
+}
+
+/// Input requested by an on-enter or on-exit system
+pub trait EventIn: SystemInput {
+    /// Convert a `StateChange` to `Self`
+    fn from_change(change: StateChange) -> Self::Inner<'static>;
+}
+
+impl EventIn for () {
+    fn from_change(_: StateChange) {}
+}
+
+impl EventIn for In<Entity> {
+    fn from_change(change: StateChange) -> Entity {
+        change.entity
+    }
+}
+
+impl EventIn for In<StateChange> {
+    fn from_change(change: StateChange) -> StateChange {
+        change
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum OnEvent {
     Entity(Box<dyn EntityEvent>),
     Command(Box<dyn CommandEvent>),
+    System(Box<dyn SystemEvent>),
 }
 
 impl OnEvent {
-    pub(crate) fn trigger(&self, entity: Entity, commands: &mut Commands) {
+    /// Initializes the event's system, if it has one. Runs once, before the event first runs, so
+    /// the system's `Local`s are kept between runs.
+    pub(crate) fn init(&mut self, world: &mut World) {
+        if let OnEvent::System(system) = self {
+            system.init(world);
+        }
+    }
+
+    pub(crate) fn trigger(&mut self, change: StateChange, world: &mut World) -> Result {
         match self {
-            OnEvent::Entity(event) => event.trigger(&mut commands.entity(entity)),
-            OnEvent::Command(event) => event.trigger(commands),
+            OnEvent::Entity(event) => event.trigger(&mut world.commands().entity(change.entity)),
+            OnEvent::Command(event) => event.trigger(&mut world.commands()),
+            OnEvent::System(system) => return system.run(change, world),
         }
+
+        OK
     }
 }
 
@@ -165,8 +313,60 @@ impl<C: Clone + Command + Sync> CommandEvent for C {
     }
 }
 
+pub(crate) trait SystemEvent: Send + Sync {
+    fn init(&mut self, world: &mut World);
+    fn run(&mut self, change: StateChange, world: &mut World) -> Result;
+}
+
+impl Debug for dyn SystemEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "System")
+    }
+}
+
+impl<S: System<Out = ()>> SystemEvent for S
+where
+    S::In: EventIn,
+{
+    fn init(&mut self, world: &mut World) {
+        self.initialize(world);
+    }
+
+    fn run(&mut self, change: StateChange, world: &mut World) -> Result {
+        System::run(self, S::In::from_change(change), world).map_err(|err| err.to_string())?;
+        OK
+    }
+}
+
+pub(crate) trait UpdateSystem: Send + Sync {
+    fn init(&mut self, world: &mut World);
+    fn run(&mut self, entity: Entity, world: &mut World) -> Result;
+}
+
+impl Debug for dyn UpdateSystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "System")
+    }
+}
+
+impl<S: System<Out = ()>> UpdateSystem for S
+where
+    S::In: TriggerIn,
+{
+    fn init(&mut self, world: &mut World) {
+        self.initialize(world);
+    }
+
+    fn run(&mut self, entity: Entity, world: &mut World) -> Result {
+        System::run(self, S::In::from_entity(entity), world).map_err(|err| err.to_string())?;
+        OK
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::any::type_name;
+
     use crate::machine::transition;
 
     use super::*;
@@ -181,6 +381,9 @@ mod tests {
     #[derive(Resource, Clone)]
     struct AnotherResource;
 
+    #[derive(Resource, Default)]
+    struct Changes(Vec<(&'static str, &'static str)>);
+
     #[test]
     fn test_triggers() {
         let mut app = App::new();
@@ -205,4 +408,34 @@ mod tests {
             "exit state triggers should run"
         );
     }
+
+    #[test]
+    fn test_system_events() {
+        let mut app = App::new();
+        app.init_resource::<Changes>()
+            .add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<StateOne, _>(always, StateTwo)
+            .system_on_exit::<StateOne, _, _>(
+                |In(change): In<StateChange>, mut changes: ResMut<Changes>| {
+                    changes.0.push((change.prev.name, change.next.name));
+                },
+            )
+            .system_on_enter::<StateTwo, _, _>(
+                |In(entity): In<Entity>, mut commands: Commands, states: Query<&StateTwo>| {
+                    assert!(states.contains(entity), "the next state should be inserted");
+                    commands.insert_resource(SomeResource);
+                },
+            );
+
+        app.world_mut().spawn((machine, StateOne));
+        app.update();
+
+        assert_eq!(
+            app.world().resource::<Changes>().0,
+            [(type_name::<StateOne>(), type_name::<StateTwo>())],
+        );
+        assert!(app.world().contains_resource::<SomeResource>());
+    }
 }
@@ -0,0 +1,198 @@
+//! Tracking how long entities have been in their states. See [`StateTimer`].
+
+use std::{any::TypeId, time::Duration};
+
+use bevy_ecs::{intern::Interned, schedule::ScheduleLabel};
+use bevy_time::Time;
+use bevy_utils::TypeIdMap;
+
+use crate::{prelude::*, set::StateSet};
+
+pub(crate) fn plug(schedule: Interned<dyn ScheduleLabel>) -> impl Fn(&mut App) {
+    move |app| {
+        app.add_systems(schedule, update_state_timers.before(StateSet::OnUpdate));
+    }
+}
+
+/// Tracks how long an entity has been in each of its current states. Add this to an entity with a
+/// state machine, and it will be updated whenever the machine transitions. Time is measured by
+/// `Time`, so it's virtual time unless the `StateMachinePlugin` runs in a fixed schedule. States
+/// that the entity was spawned in count from when the timer was first updated.
+#[derive(Component, Clone, Debug, Default)]
+pub struct StateTimer {
+    /// `Time::elapsed` as of the last update
+    now: Duration,
+    /// When the timer was first updated
+    start: Option<Duration>,
+    /// When each state was entered
+    entered: TypeIdMap<Duration>,
+}
+
+impl StateTimer {
+    /// How long the entity has been in the given state. Returns `None` if the entity isn't in the
+    /// state, or if it was spawned in the state and the timer hasn't been updated yet.
+    pub fn elapsed<S: Component>(&self) -> Option<Duration> {
+        self.elapsed_by_id(TypeId::of::<S>())
+    }
+
+    /// [`StateTimer::elapsed`] with the state's `TypeId`
+    pub fn elapsed_by_id(&self, state: TypeId) -> Option<Duration> {
+        self.entered_at_by_id(state)
+            .map(|entered| self.now.saturating_sub(entered))
+    }
+
+    /// When the entity entered the given state, measured by `Time::elapsed`. Returns `None` like
+    /// [`StateTimer::elapsed`].
+    pub fn entered_at<S: Component>(&self) -> Option<Duration> {
+        self.entered_at_by_id(TypeId::of::<S>())
+    }
+
+    /// [`StateTimer::entered_at`] with the state's `TypeId`
+    pub fn entered_at_by_id(&self, state: TypeId) -> Option<Duration> {
+        self.entered.get(&state).copied()
+    }
+
+    pub(crate) fn enter(&mut self, state: TypeId) {
+        self.entered.insert(state, self.now);
+    }
+
+    pub(crate) fn exit(&mut self, state: TypeId) {
+        self.entered.remove(&state);
+    }
+
+    /// How long it has been since the timer was first updated
+    #[cfg(feature = "serde")]
+    pub(crate) fn since_start(&self) -> Option<Duration> {
+        self.start.map(|start| self.now.saturating_sub(start))
+    }
+
+    /// The states that the timer has seen the entity enter
+    #[cfg(feature = "serde")]
+    pub(crate) fn entered(&self) -> impl Iterator<Item = TypeId> + '_ {
+        self.entered.keys().copied()
+    }
+
+    /// Rebuilds a timer from how long ago it started and how long the entity has been in each
+    /// state, relative to `now`
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore(
+        now: Duration,
+        since_start: Option<Duration>,
+        elapsed: impl IntoIterator<Item = (TypeId, Duration)>,
+    ) -> Self {
+        Self {
+            now,
+            start: since_start.map(|since_start| now.saturating_sub(since_start)),
+            entered: elapsed
+                .into_iter()
+                .map(|(state, elapsed)| (state, now.saturating_sub(elapsed)))
+                .collect(),
+        }
+    }
+}
+
+fn update_state_timers(
+    mut commands: Commands,
+    mut timers: Query<(Entity, &mut StateTimer)>,
+    time: Option<Res<Time>>,
+) {
+    let Some(time) = time else {
+        return;
+    };
+    let now = time.elapsed();
+
+    for (entity, mut timer) in &mut timers {
+        timer.now = now;
+
+        if timer.start.is_none() {
+            timer.start = Some(now);
+            commands.entity(entity).queue(start_timer);
+        }
+    }
+}
+
+/// Makes the states that the entity is in when its timer starts count from then
+fn start_timer(mut entity: EntityWorldMut) {
+    let states = current_states(entity.as_readonly());
+    if let Some(mut timer) = entity.get_mut::<StateTimer>() {
+        let start = timer.now;
+        timer.entered = states.into_iter().map(|state| (state, start)).collect();
+    }
+}
+
+/// The states that the entity's `StateMachine` or `StateMachineDef` says it's in
+fn current_states(entity: EntityRef) -> Vec<TypeId> {
+    let states = match entity.get::<StateMachine>() {
+        Some(machine) => machine.current_states(entity),
+        None => entity
+            .get::<StateMachineDef>()
+            .and_then(|def| def.current_states(entity).ok())
+            .unwrap_or_default(),
+    };
+
+    states.into_iter().map(|state| state.id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::transition;
+
+    use super::*;
+
+    #[derive(Component, Clone)]
+    struct Idle;
+    #[derive(Component, Clone)]
+    struct Walk;
+    #[derive(Component, Clone)]
+    struct Never;
+
+    #[derive(Resource)]
+    struct Go;
+
+    fn go(go: Option<Res<Go>>) -> bool {
+        go.is_some()
+    }
+
+    #[test]
+    fn test_state_timer() {
+        let mut app = App::new();
+        app.init_resource::<Time>()
+            .add_systems(Update, (update_state_timers, transition).chain());
+
+        let machine = StateMachine::default().trans::<Idle, _>(go, Walk);
+        let entity = app
+            .world_mut()
+            .spawn((machine, Idle, StateTimer::default()))
+            .id();
+
+        let advance = |app: &mut App, seconds| {
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs(seconds));
+            app.update();
+        };
+
+        app.update();
+        advance(&mut app, 2);
+        let timer = app.world().get::<StateTimer>(entity).unwrap();
+        assert_eq!(timer.elapsed::<Idle>(), Some(Duration::from_secs(2)));
+
+        app.world_mut().insert_resource(Go);
+        advance(&mut app, 1);
+        advance(&mut app, 1);
+
+        let entity = app.world().entity(entity);
+        let timer = entity.get::<StateTimer>().unwrap();
+        assert_eq!(timer.entered_at::<Walk>(), Some(Duration::from_secs(3)));
+        assert_eq!(timer.elapsed::<Walk>(), Some(Duration::from_secs(1)));
+        // States that the entity left, or was never in, have no time
+        assert_eq!(timer.elapsed::<Idle>(), None);
+        assert_eq!(timer.entered_at::<Idle>(), None);
+        assert_eq!(timer.elapsed::<Never>(), None);
+
+        let current = entity.get::<StateMachine>().unwrap().current_state(entity);
+        let current = current.unwrap();
+        assert_eq!(current.id, TypeId::of::<Walk>());
+        assert_eq!(current.elapsed, Some(Duration::from_secs(1)));
+    }
+}
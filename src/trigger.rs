@@ -3,6 +3,7 @@
 
 #[cfg(feature = "leafwing_input")]
 mod input;
+mod time;
 
 use bevy_derive::{Deref, DerefMut};
 use bevy_ecs::{intern::Interned, schedule::ScheduleLabel};
@@ -18,9 +19,15 @@ pub use input::{
 };
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+pub use time::{
+    frames_in_state, random_time_in_state, time_in_state, FramesInState, RandomTimeInState,
+    TimeInState,
+};
 
-use std::{convert::Infallible, fmt::Debug};
+use std::{any::type_name, borrow::Cow, convert::Infallible, fmt::Debug};
 
+#[cfg(feature = "serde")]
+use crate::OK;
 use crate::{prelude::*, set::StateSet};
 
 pub(crate) fn plug(schedule: Interned<dyn ScheduleLabel>) -> impl Fn(&mut App) {
@@ -62,10 +69,11 @@ impl TriggerIn for In<Entity> {
 /// Output returned from a trigger. Indicates whether the transition will occur, and may include
 /// data given to `StateMachine::trans_builder`.
 pub trait TriggerOut {
-    /// Data given to `StateMachine::trans_builder` on a success
-    type Ok;
+    /// Data given to `StateMachine::trans_builder` on a success. Must be `Send`, since triggers
+    /// are checked in parallel.
+    type Ok: 'static + Send;
     /// Data given to `StataMachine::trans_builder` if this trigger fails and is negated
-    type Err;
+    type Err: 'static + Send;
 
     /// Convert `Self` to a `Result`
     fn into_result(self) -> Result<Self::Ok, Self::Err>;
@@ -84,7 +92,25 @@ impl TriggerOut for bool {
     }
 }
 
-impl<T> TriggerOut for Option<T> {
+/// A utility score, output by triggers of transitions that are chosen by score. Triggers if it's
+/// positive. See `Selection::Utility`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Score(pub f32);
+
+impl TriggerOut for Score {
+    type Ok = Score;
+    type Err = Score;
+
+    fn into_result(self) -> Result<Score, Score> {
+        if self.0 > 0. {
+            Ok(self)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<T: 'static + Send> TriggerOut for Option<T> {
     type Ok = T;
     type Err = ();
 
@@ -93,7 +119,7 @@ impl<T> TriggerOut for Option<T> {
     }
 }
 
-impl<Ok, Err> TriggerOut for Result<Ok, Err> {
+impl<Ok: 'static + Send, Err: 'static + Send> TriggerOut for Result<Ok, Err> {
     type Ok = Ok;
     type Err = Err;
 
@@ -123,14 +149,7 @@ pub trait IntoTrigger<Marker>: Sized {
     fn into_trigger(self) -> Self::Trigger;
 
     /// Negates the trigger. Do not override.
-    fn not(
-        self,
-    ) -> impl EntityTrigger<
-        Out = Result<
-            <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-            <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-        >,
-    > {
+    fn not(self) -> NotTrigger<Self::Trigger> {
         NotTrigger(self.into_trigger())
     }
 
@@ -138,18 +157,7 @@ pub trait IntoTrigger<Marker>: Sized {
     fn and<Marker2, T: IntoTrigger<Marker2>>(
         self,
         other: T,
-    ) -> impl EntityTrigger<
-        Out = Result<
-            (
-                <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-                <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-            ),
-            Either<
-                <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-                <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-            >,
-        >,
-    > {
+    ) -> AndTrigger<Self::Trigger, T::Trigger> {
         AndTrigger(self.into_trigger(), other.into_trigger())
     }
 
@@ -157,15 +165,7 @@ pub trait IntoTrigger<Marker>: Sized {
     fn ignore_and<Marker2, T: IntoTrigger<Marker2>>(
         self,
         other: T,
-    ) -> impl EntityTrigger<
-        Out = Result<
-            <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-            Either<
-                <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-                <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-            >,
-        >,
-    > {
+    ) -> IgnoreAndTrigger<Self::Trigger, T::Trigger> {
         IgnoreAndTrigger(self.into_trigger(), other.into_trigger())
     }
 
@@ -173,18 +173,7 @@ pub trait IntoTrigger<Marker>: Sized {
     fn or<Marker2, T: IntoTrigger<Marker2>>(
         self,
         other: T,
-    ) -> impl EntityTrigger<
-        Out = Result<
-            Either<
-                <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-                <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Ok,
-            >,
-            (
-                <<Self::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-                <<T::Trigger as EntityTrigger>::Out as TriggerOut>::Err,
-            ),
-        >,
-    > {
+    ) -> OrTrigger<Self::Trigger, T::Trigger> {
         OrTrigger(self.into_trigger(), other.into_trigger())
     }
 }
@@ -198,12 +187,13 @@ where
     type Trigger = SystemTrigger<T::System>;
 
     fn into_trigger(self) -> Self::Trigger {
-        SystemTrigger(IntoSystem::into_system(self))
+        SystemTrigger(IntoSystem::into_system(self), type_name::<T>())
     }
 }
 
 /// Types that implement this may be used in [`StateMachine`]s to transition from one state to
-/// another. Look at an example for implementing this trait, since it can be tricky.
+/// another, if they're also `Clone`. Look at an example for implementing this trait, since it can
+/// be tricky.
 pub trait EntityTrigger: 'static + Send + Sync {
     /// The trigger's output. See [`TriggerOut`].
     type Out: TriggerOut;
@@ -212,6 +202,25 @@ pub trait EntityTrigger: 'static + Send + Sync {
     fn init(&mut self, world: &mut World);
     /// Checks whether the state machine should transition
     fn check(&mut self, entity: Entity, world: &World) -> Result<Self::Out>;
+    /// A human-readable name for this trigger, for introspection and debug information. Defaults
+    /// to the type name. Triggers made from systems use the system's name.
+    fn name(&self) -> Cow<'static, str> {
+        type_name::<Self>().into()
+    }
+    /// Saves this trigger's data, like a timer's progress, so that it survives saving and loading
+    /// the machine. See `MachineRegistry::save`. Defaults to `None`, so the trigger starts over
+    /// when loaded.
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let _ = world;
+        None
+    }
+    /// Loads the data from [`EntityTrigger::save`]. Called after [`EntityTrigger::init`].
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let _ = (data, world);
+        OK
+    }
 }
 
 impl<T: EntityTrigger> IntoTrigger<()> for T {
@@ -232,10 +241,25 @@ impl<O: 'static + TriggerOut> EntityTrigger for Box<dyn EntityTrigger<Out = O>>
     fn check(&mut self, entity: Entity, world: &World) -> Result<Self::Out> {
         (**self).check(entity, world)
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        (**self).name()
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        (**self).save(world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        (**self).load(data, world)
+    }
 }
 
 /// The trigger form of a system. See [`IntoSystem`].
-pub struct SystemTrigger<T: ReadOnlySystem>(T);
+#[derive(Clone)]
+pub struct SystemTrigger<T: ReadOnlySystem>(T, &'static str);
 
 impl<T: ReadOnlySystem> EntityTrigger for SystemTrigger<T>
 where
@@ -245,15 +269,20 @@ where
     type Out = T::Out;
 
     fn init(&mut self, world: &mut World) {
-        let Self(t) = self;
+        let Self(t, _) = self;
         t.initialize(world);
     }
 
     fn check(&mut self, entity: Entity, world: &World) -> Result<Self::Out> {
-        let Self(t) = self;
+        let Self(t, _) = self;
         Ok(t.run_readonly(T::In::from_entity(entity), world)
             .map_err(|err| err.to_string())?)
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(_, name) = self;
+        (*name).into()
+    }
 }
 
 /// Trigger that always transitions
@@ -262,7 +291,7 @@ pub fn always() -> bool {
 }
 
 /// Negates the given trigger
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct NotTrigger<T: EntityTrigger>(pub T);
 
 impl<T: EntityTrigger> EntityTrigger for NotTrigger<T> {
@@ -280,10 +309,27 @@ impl<T: EntityTrigger> EntityTrigger for NotTrigger<T> {
             Err(err) => Ok(err),
         })
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t) = self;
+        format!("not({})", t.name()).into()
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let Self(t) = self;
+        t.save(world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let Self(t) = self;
+        t.load(data, world)
+    }
 }
 
 /// Combines two triggers by logical AND
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct AndTrigger<T: EntityTrigger, U: EntityTrigger>(pub T, pub U);
 
 impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for AndTrigger<T, U> {
@@ -313,10 +359,27 @@ impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for AndTrigger<T, U> {
             },
         )))
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t, u) = self;
+        format!("and({}, {})", t.name(), u.name()).into()
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let Self(t, u) = self;
+        save_pair(t, u, world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let Self(t, u) = self;
+        load_pair(t, u, data, world)
+    }
 }
 
 /// Combines two triggers by logical AND, discarding the output of the first
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct IgnoreAndTrigger<T: EntityTrigger, U: EntityTrigger>(pub T, pub U);
 
 impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for IgnoreAndTrigger<T, U> {
@@ -340,10 +403,27 @@ impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for IgnoreAndTrigger<T, U
         }
         Ok(u.check(entity, world)?.into_result().map_err(Either::Right))
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t, u) = self;
+        format!("ignore_and({}, {})", t.name(), u.name()).into()
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let Self(t, u) = self;
+        save_pair(t, u, world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let Self(t, u) = self;
+        load_pair(t, u, data, world)
+    }
 }
 
 /// Combines two triggers by logical OR
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct OrTrigger<T: EntityTrigger, U: EntityTrigger>(pub T, pub U);
 
 impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for OrTrigger<T, U> {
@@ -370,12 +450,69 @@ impl<T: EntityTrigger, U: EntityTrigger> EntityTrigger for OrTrigger<T, U> {
             },
         }
     }
+
+    fn name(&self) -> Cow<'static, str> {
+        let Self(t, u) = self;
+        format!("or({}, {})", t.name(), u.name()).into()
+    }
+
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let Self(t, u) = self;
+        save_pair(t, u, world)
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let Self(t, u) = self;
+        load_pair(t, u, data, world)
+    }
+}
+
+/// Saves both triggers' data as a pair, for combinators
+#[cfg(feature = "serde")]
+fn save_pair(
+    t: &impl EntityTrigger,
+    u: &impl EntityTrigger,
+    world: &World,
+) -> Option<serde_json::Value> {
+    match (t.save(world), u.save(world)) {
+        (None, None) => None,
+        (t, u) => Some(serde_json::json!([t, u])),
+    }
+}
+
+/// Loads the data from [`save_pair`]
+#[cfg(feature = "serde")]
+fn load_pair(
+    t: &mut impl EntityTrigger,
+    u: &mut impl EntityTrigger,
+    data: serde_json::Value,
+    world: &World,
+) -> Result {
+    let (t_data, u_data) = serde_json::from_value::<(Option<_>, Option<_>)>(data)?;
+
+    if let Some(data) = t_data {
+        t.load(data, world)?;
+    }
+    if let Some(data) = u_data {
+        u.load(data, world)?;
+    }
+
+    OK
 }
 
 /// Marker component that represents that the current state has completed. Removed from every entity
-/// each frame after checking triggers. To be used with [`done`].
+/// each frame after checking triggers, unless a state machine inserted it upon reaching a final
+/// state, in which case it's removed a frame later. To be used with [`done`]. See
+/// `StateMachine::final_state`.
 #[derive(Component, Debug, Eq, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "reflect",
+    derive(bevy_reflect::Reflect),
+    reflect(Component, Clone, Debug, PartialEq)
+)]
 #[component(storage = "SparseSet")]
 pub enum Done {
     /// Success variant
@@ -386,7 +523,7 @@ pub enum Done {
 
 /// Trigger that transitions if the entity has the [`Done`] component. Provide `Some(Done::Variant)`
 /// to transition upon that particular variant, or `None` to transition upon either.
-pub fn done(expected: Option<Done>) -> impl EntityTrigger<Out = bool> {
+pub fn done(expected: Option<Done>) -> impl EntityTrigger<Out = bool> + Clone {
     (move |In(entity): In<Entity>, dones: Query<&Done>| {
         dones
             .get(entity)
@@ -410,8 +547,21 @@ pub fn on_message<T: Clone + Message>(
     reader.read().last().cloned()
 }
 
-pub(crate) fn remove_done_markers(mut commands: Commands, dones: Query<Entity, With<Done>>) {
-    for done in &dones {
-        commands.entity(done).remove::<Done>();
+/// Marks a [`Done`] that a state machine inserted upon reaching a final state, so it's kept until
+/// after the machines' next transitions
+#[derive(Component)]
+#[component(storage = "SparseSet")]
+pub(crate) struct KeepDone;
+
+pub(crate) fn remove_done_markers(
+    mut commands: Commands,
+    dones: Query<(Entity, Has<KeepDone>), With<Done>>,
+) {
+    for (done, keep) in &dones {
+        if keep {
+            commands.entity(done).remove::<KeepDone>();
+        } else {
+            commands.entity(done).remove::<Done>();
+        }
     }
 }
@@ -10,7 +10,7 @@ use crate::prelude::*;
 pub fn value<A: Actionlike>(
     action: A,
     bounds: Range<f32>,
-) -> impl EntityTrigger<Out = Result<f32, f32>> {
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         let value = actors
             .get(entity)
@@ -32,17 +32,25 @@ pub fn value<A: Actionlike>(
 }
 
 /// Unbounded [`value`]
-pub fn value_unbounded(action: impl Actionlike) -> impl EntityTrigger<Out = Result<f32, f32>> {
+pub fn value_unbounded(
+    action: impl Actionlike,
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     value(action, f32::NEG_INFINITY..f32::INFINITY)
 }
 
 /// [`value`] with only a minimum bound
-pub fn value_min(action: impl Actionlike, min: f32) -> impl EntityTrigger<Out = Result<f32, f32>> {
+pub fn value_min(
+    action: impl Actionlike,
+    min: f32,
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     value(action, min..f32::INFINITY)
 }
 
 /// [`value`] with only a maximum bound
-pub fn value_max(action: impl Actionlike, max: f32) -> impl EntityTrigger<Out = Result<f32, f32>> {
+pub fn value_max(
+    action: impl Actionlike,
+    max: f32,
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     value(action, f32::NEG_INFINITY..max)
 }
 
@@ -50,7 +58,7 @@ pub fn value_max(action: impl Actionlike, max: f32) -> impl EntityTrigger<Out =
 pub fn clamped_value<A: Actionlike>(
     action: A,
     bounds: Range<f32>,
-) -> impl EntityTrigger<Out = Result<f32, f32>> {
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         let value = actors
             .get(entity)
@@ -74,7 +82,7 @@ pub fn clamped_value<A: Actionlike>(
 /// Unbounded [`clamped_value`]
 pub fn clamped_value_unbounded(
     action: impl Actionlike,
-) -> impl EntityTrigger<Out = Result<f32, f32>> {
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     clamped_value(action, f32::NEG_INFINITY..f32::INFINITY)
 }
 
@@ -82,7 +90,7 @@ pub fn clamped_value_unbounded(
 pub fn clamped_value_min(
     action: impl Actionlike,
     min: f32,
-) -> impl EntityTrigger<Out = Result<f32, f32>> {
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     clamped_value(action, min..f32::INFINITY)
 }
 
@@ -90,7 +98,7 @@ pub fn clamped_value_min(
 pub fn clamped_value_max(
     action: impl Actionlike,
     max: f32,
-) -> impl EntityTrigger<Out = Result<f32, f32>> {
+) -> impl EntityTrigger<Out = Result<f32, f32>> + Clone {
     clamped_value(action, f32::NEG_INFINITY..max)
 }
 
@@ -105,7 +113,7 @@ pub fn axis_pair<A: Actionlike>(
     action: A,
     length_bounds: Range<f32>,
     rotation_bounds: Range<Dir2>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         let axis_pair = actors
             .get(entity)
@@ -145,7 +153,7 @@ pub fn axis_pair<A: Actionlike>(
 /// Unbounded [`axis_pair`]
 pub fn axis_pair_unbounded(
     action: impl Actionlike,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     axis_pair(action, 0.0..f32::INFINITY, Dir2::Y..Dir2::Y)
 }
 
@@ -153,7 +161,7 @@ pub fn axis_pair_unbounded(
 pub fn axis_pair_min_length(
     action: impl Actionlike,
     min_length: f32,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     axis_pair(action, min_length..f32::INFINITY, Dir2::Y..Dir2::Y)
 }
 
@@ -161,7 +169,7 @@ pub fn axis_pair_min_length(
 pub fn axis_pair_max_length(
     action: impl Actionlike,
     max_length: f32,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     axis_pair(action, 0.0..max_length, Dir2::Y..Dir2::Y)
 }
 
@@ -169,7 +177,7 @@ pub fn axis_pair_max_length(
 pub fn axis_pair_length_bounds(
     action: impl Actionlike,
     length_bounds: Range<f32>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     axis_pair(action, length_bounds, Dir2::Y..Dir2::Y)
 }
 
@@ -177,7 +185,7 @@ pub fn axis_pair_length_bounds(
 pub fn axis_pair_rotation_bounds(
     action: impl Actionlike,
     rotation_bounds: Range<Dir2>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     axis_pair(action, 0.0..f32::INFINITY, rotation_bounds)
 }
 
@@ -186,7 +194,7 @@ pub fn clamped_axis_pair<A: Actionlike>(
     action: A,
     length_bounds: Range<f32>,
     rotation_bounds: Range<Dir2>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         let axis_pair = actors
             .get(entity)
@@ -226,7 +234,7 @@ pub fn clamped_axis_pair<A: Actionlike>(
 /// Unbounded [`clamped_axis_pair`]
 pub fn clamped_axis_pair_unbounded(
     action: impl Actionlike,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     clamped_axis_pair(action, 0.0..f32::INFINITY, Dir2::Y..Dir2::Y)
 }
 
@@ -234,7 +242,7 @@ pub fn clamped_axis_pair_unbounded(
 pub fn clamped_axis_pair_min_length(
     action: impl Actionlike,
     min_length: f32,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     clamped_axis_pair(action, min_length..f32::INFINITY, Dir2::Y..Dir2::Y)
 }
 
@@ -242,7 +250,7 @@ pub fn clamped_axis_pair_min_length(
 pub fn clamped_axis_pair_max_length(
     action: impl Actionlike,
     max_length: f32,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     clamped_axis_pair(action, 0.0..max_length, Dir2::Y..Dir2::Y)
 }
 
@@ -250,7 +258,7 @@ pub fn clamped_axis_pair_max_length(
 pub fn clamped_axis_pair_length_bounds(
     action: impl Actionlike,
     length_bounds: Range<f32>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     clamped_axis_pair(action, length_bounds, Dir2::Y..Dir2::Y)
 }
 
@@ -258,12 +266,12 @@ pub fn clamped_axis_pair_length_bounds(
 pub fn clamped_axis_pair_rotation_bounds(
     action: impl Actionlike,
     rotation_bounds: Range<Dir2>,
-) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> {
+) -> impl EntityTrigger<Out = Result<Vec2, Vec2>> + Clone {
     clamped_axis_pair(action, 0.0..f32::INFINITY, rotation_bounds)
 }
 
 /// Trigger that transitions upon pressing the given [`Actionlike`]
-pub fn just_pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> {
+pub fn just_pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         actors
             .get(entity)
@@ -279,7 +287,7 @@ pub fn just_pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool>
 }
 
 /// Trigger that transitions while pressing the given [`Actionlike`]
-pub fn pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> {
+pub fn pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         actors
             .get(entity)
@@ -295,7 +303,7 @@ pub fn pressed<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> {
 }
 
 /// Trigger that transitions upon releasing the given [`Actionlike`]
-pub fn just_released<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> {
+pub fn just_released<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         actors
             .get(entity)
@@ -311,7 +319,9 @@ pub fn just_released<A: Actionlike>(action: A) -> impl EntityTrigger<Out = bool>
 }
 
 /// Provides the given [`Actionlike`]'s [`ActionData`]
-pub fn action_data<A: Actionlike>(action: A) -> impl EntityTrigger<Out = Option<ActionData>> {
+pub fn action_data<A: Actionlike>(
+    action: A,
+) -> impl EntityTrigger<Out = Option<ActionData>> + Clone {
     (move |In(entity): In<Entity>, actors: Query<&ActionState<A>>| {
         actors
             .get(entity)
@@ -0,0 +1,339 @@
+use std::{marker::PhantomData, ops::Range, time::Duration};
+
+use bevy_time::Time;
+
+#[cfg(feature = "serde")]
+use crate::OK;
+use crate::{machine::MachineRuns, prelude::*};
+
+/// Trigger that transitions once the entity has been in its current state for `seconds`, measured
+/// by `Time<T>`. Use `Real`, `Virtual`, or `Fixed` for `T`, or `()` for the schedule's default
+/// clock. `Time<Virtual>` doesn't advance while paused, so neither does this trigger.
+///
+/// The time is measured from when the entity entered the state, or from the machine's first run for
+/// a state that the entity was spawned in. Each entity that uses a `StateMachineDef` has its own
+/// trigger, and so its own time.
+///
+/// # Panics
+///
+/// Panics if `seconds` is negative or not finite.
+pub fn time_in_state<T: Default + Send + Sync + 'static>(seconds: f32) -> TimeInState<T> {
+    assert!(
+        seconds.is_finite() && seconds >= 0.,
+        "`time_in_state` requires a finite, non-negative duration, but got {seconds}",
+    );
+
+    TimeInState {
+        duration: Duration::from_secs_f32(seconds),
+        start: None,
+        phantom: PhantomData,
+    }
+}
+
+/// Trigger that transitions once the entity has been in its current state for a random duration
+/// in the given range of seconds, which is picked again whenever the machine transitions. See
+/// [`time_in_state`].
+///
+/// # Panics
+///
+/// Panics if the range's bounds are negative or not finite, or if its start is after its end.
+pub fn random_time_in_state<T: Default + Send + Sync + 'static>(
+    seconds: Range<f32>,
+) -> RandomTimeInState<T> {
+    assert!(
+        seconds.start.is_finite() && seconds.end.is_finite(),
+        "`random_time_in_state` requires a finite range, but got {seconds:?}",
+    );
+    assert!(
+        0. <= seconds.start && seconds.start <= seconds.end,
+        "`random_time_in_state` requires a non-negative, non-reversed range, but got {seconds:?}",
+    );
+
+    RandomTimeInState {
+        seconds,
+        inner: time_in_state(0.),
+    }
+}
+
+/// Trigger that transitions once the entity has been in its current state for `frames` runs of the
+/// machine. If the `StateMachinePlugin` runs in a fixed schedule, these are fixed frames. Like
+/// [`time_in_state`], frames are counted from the machine's first run for a state that the entity
+/// was spawned in, and a machine that runs to completion doesn't count its extra iterations.
+pub fn frames_in_state(frames: u32) -> FramesInState {
+    FramesInState {
+        frames,
+        start: None,
+    }
+}
+
+fn runs(world: &World) -> u64 {
+    world.get_resource::<MachineRuns>().map_or(0, |runs| runs.0)
+}
+
+fn elapsed<T: Default + Send + Sync + 'static>(world: &World) -> Result<Duration> {
+    Ok(world
+        .get_resource::<Time<T>>()
+        .ok_or("time triggers require the corresponding `Time` resource")?
+        .elapsed())
+}
+
+/// Trigger built by [`time_in_state`]
+#[derive(Debug)]
+pub struct TimeInState<T> {
+    duration: Duration,
+    start: Option<Duration>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Clone for TimeInState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            duration: self.duration,
+            start: self.start,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: Default + Send + Sync + 'static> EntityTrigger for TimeInState<T> {
+    type Out = bool;
+
+    fn init(&mut self, world: &mut World) {
+        self.start = elapsed::<T>(world).ok();
+    }
+
+    fn check(&mut self, _: Entity, world: &World) -> Result<bool> {
+        let now = elapsed::<T>(world)?;
+        let start = *self.start.get_or_insert(now);
+        Ok(now.saturating_sub(start) >= self.duration)
+    }
+
+    /// Saves how long the entity has been in the state, since the clock may be different when
+    /// loaded
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        let now = elapsed::<T>(world).ok()?;
+        serde_json::to_value(now.saturating_sub(self.start?)).ok()
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let in_state = serde_json::from_value::<Duration>(data)?;
+        self.start = Some(elapsed::<T>(world)?.saturating_sub(in_state));
+        OK
+    }
+}
+
+/// Trigger built by [`random_time_in_state`]
+#[derive(Debug)]
+pub struct RandomTimeInState<T> {
+    seconds: Range<f32>,
+    inner: TimeInState<T>,
+}
+
+impl<T> Clone for RandomTimeInState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            seconds: self.seconds.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Default + Send + Sync + 'static> EntityTrigger for RandomTimeInState<T> {
+    type Out = bool;
+
+    fn init(&mut self, world: &mut World) {
+        let Range { start, end } = self.seconds;
+        self.inner.duration = Duration::from_secs_f32(start + (end - start) * fastrand::f32());
+        self.inner.init(world);
+    }
+
+    fn check(&mut self, entity: Entity, world: &World) -> Result<bool> {
+        self.inner.check(entity, world)
+    }
+
+    /// Saves the chosen duration along with the elapsed time
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        Some(serde_json::json!([
+            self.inner.duration,
+            self.inner.save(world)?
+        ]))
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let (duration, elapsed) = serde_json::from_value::<(Duration, _)>(data)?;
+        self.inner.duration = duration;
+        self.inner.load(elapsed, world)
+    }
+}
+
+/// Trigger built by [`frames_in_state`]
+#[derive(Clone, Debug)]
+pub struct FramesInState {
+    frames: u32,
+    /// The machine's run in which the entity entered the state
+    start: Option<u64>,
+}
+
+impl EntityTrigger for FramesInState {
+    type Out = bool;
+
+    fn init(&mut self, world: &mut World) {
+        self.start = Some(runs(world));
+    }
+
+    fn check(&mut self, _: Entity, world: &World) -> Result<bool> {
+        let now = runs(world);
+        let start = *self.start.get_or_insert(now);
+        Ok(now.saturating_sub(start) >= u64::from(self.frames))
+    }
+
+    /// Saves how many frames the entity has been in the state, since the machine's run count may
+    /// be different when loaded
+    #[cfg(feature = "serde")]
+    fn save(&self, world: &World) -> Option<serde_json::Value> {
+        Some(runs(world).saturating_sub(self.start?).into())
+    }
+
+    #[cfg(feature = "serde")]
+    fn load(&mut self, data: serde_json::Value, world: &World) -> Result {
+        let frames = serde_json::from_value::<u64>(data)?;
+        self.start = Some(runs(world).saturating_sub(frames));
+        OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_time::Virtual;
+
+    use crate::machine::transition;
+
+    use super::*;
+
+    #[derive(Component, Clone)]
+    struct Idle;
+    #[derive(Component, Clone)]
+    struct Wander;
+
+    fn advance(app: &mut App, seconds: f32) {
+        app.world_mut()
+            .resource_mut::<Time<Virtual>>()
+            .advance_by(Duration::from_secs_f32(seconds));
+    }
+
+    #[test]
+    fn test_time_in_state() {
+        let mut app = App::new();
+        app.init_resource::<Time<Virtual>>()
+            .add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(time_in_state::<Virtual>(1.), Wander)
+            .trans::<Wander, _>(random_time_in_state::<Virtual>(1.0..2.0), Idle);
+        let entity = app.world_mut().spawn((machine, Idle)).id();
+
+        app.update();
+        advance(&mut app, 0.5);
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+
+        advance(&mut app, 0.6);
+        app.update();
+        assert!(app.world().get::<Wander>(entity).is_some());
+
+        advance(&mut app, 0.9);
+        app.update();
+        assert!(app.world().get::<Wander>(entity).is_some());
+
+        advance(&mut app, 1.2);
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+    }
+
+    #[test]
+    fn test_time_in_state_def() {
+        let mut app = App::new();
+        app.init_resource::<Time<Virtual>>()
+            .add_systems(Update, transition);
+
+        let def = StateMachineDef::new(
+            StateMachine::default().trans::<Idle, _>(time_in_state::<Virtual>(1.), Wander),
+        );
+        let early = app.world_mut().spawn((def.clone(), Idle)).id();
+
+        app.update();
+        advance(&mut app, 0.6);
+        let late = app.world_mut().spawn((def, Idle)).id();
+        app.update();
+
+        advance(&mut app, 0.5);
+        app.update();
+        assert!(app.world().get::<Wander>(early).is_some());
+        assert!(app.world().get::<Idle>(late).is_some());
+
+        // The early entity's transition doesn't restart the late entity's timer
+        advance(&mut app, 0.6);
+        app.update();
+        assert!(app.world().get::<Wander>(late).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "non-reversed range")]
+    fn test_reversed_random_time() {
+        random_time_in_state::<Virtual>(3.0..1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "non-negative duration")]
+    fn test_negative_time() {
+        time_in_state::<Virtual>(-1.);
+    }
+
+    #[test]
+    fn test_frames_in_state() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(frames_in_state(3), Wander)
+            .trans::<Wander, _>(frames_in_state(1), Idle);
+        let entity = app.world_mut().spawn((machine, Idle)).id();
+
+        app.update();
+        app.update();
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+
+        app.update();
+        assert!(app.world().get::<Wander>(entity).is_some());
+
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+    }
+
+    #[test]
+    fn test_frames_in_state_run_to_completion() {
+        let mut app = App::new();
+        app.add_systems(Update, transition);
+
+        let machine = StateMachine::default()
+            .trans::<Idle, _>(frames_in_state(1), Wander)
+            .trans::<Wander, _>(frames_in_state(1), Idle)
+            .run_to_completion(10);
+        let entity = app.world_mut().spawn((machine, Idle)).id();
+
+        // The machine's extra iterations aren't frames
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+
+        app.update();
+        assert!(app.world().get::<Wander>(entity).is_some());
+
+        app.update();
+        assert!(app.world().get::<Idle>(entity).is_some());
+    }
+}